@@ -0,0 +1,217 @@
+use axum::http::{header, HeaderMap};
+use bytes::Bytes;
+use moka::future::Cache;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tiny_skia::Pixmap;
+use tokio::sync::watch;
+
+use crate::fetcher::FetchError;
+use crate::render::{decode_image_for, ImageBox};
+
+/// Fetched bodies are kept just long enough for concurrent loads of one URL at
+/// different sizes to share a single fetch.
+const BODY_CACHE_TTL: Duration = Duration::from_secs(10);
+const BODY_CACHE_MAX_BYTES: u64 = 32 * 1024 * 1024;
+
+/// One encoded render, ready to be sent as-is.
+pub struct CachedRender {
+    pub content_type: &'static str,
+    /// Quoted strong validator, e.g. `"3f2a…"`.
+    pub etag: String,
+    pub bytes: Bytes,
+}
+
+/// Encoded-output cache keyed by a canonical hash of the request payload, the
+/// negotiated output format and the template version in effect.
+///
+/// The key doubles as the ETag: identical requests produce identical images as
+/// long as every image they reference loads, so `If-None-Match` can be answered
+/// without rendering or even a lookup. Handlers never store a render in which a
+/// fetched image fell back, so such a render gets no ETag either.
+pub struct OutputCache {
+    cache: Cache<String, Arc<CachedRender>>,
+    template_version: watch::Receiver<String>,
+}
+
+impl OutputCache {
+    /// `template_version` follows `TemplateEngine` reloads, so a template edit
+    /// changes every key and stale renders simply age out.
+    pub fn new(max_bytes: u64, ttl: Duration, template_version: watch::Receiver<String>) -> Self {
+        let cache = Cache::builder()
+            .weigher(|_key: &String, value: &Arc<CachedRender>| {
+                value.bytes.len().try_into().unwrap_or(u32::MAX)
+            })
+            .max_capacity(max_bytes)
+            .time_to_live(ttl)
+            .build();
+        Self { cache, template_version }
+    }
+
+    /// `OUTPUT_CACHE_MAX_BYTES` (default 256 MiB) and `OUTPUT_CACHE_TTL_SECS` (default 300).
+    pub fn from_env(template_version: watch::Receiver<String>) -> Self {
+        let max_bytes = std::env::var("OUTPUT_CACHE_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(256 * 1024 * 1024);
+        let ttl_secs = std::env::var("OUTPUT_CACHE_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300);
+        Self::new(max_bytes, Duration::from_secs(ttl_secs), template_version)
+    }
+
+    /// Canonical key for a request. serde emits struct fields in declaration
+    /// order, so the JSON encoding of the parsed payload is stable regardless of
+    /// how the caller ordered or spaced its body.
+    pub fn key<T: Serialize>(&self, route: &str, payload: &T, content_type: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.template_version.borrow().as_bytes());
+        hasher.update([0]);
+        hasher.update(route.as_bytes());
+        hasher.update([0]);
+        hasher.update(content_type.as_bytes());
+        hasher.update([0]);
+        // Serializing plain request structs cannot fail.
+        hasher.update(serde_json::to_vec(payload).unwrap_or_default());
+        format!("\"{:x}\"", hasher.finalize())
+    }
+
+    pub async fn get(&self, route: &'static str, key: &str) -> Option<Arc<CachedRender>> {
+        let hit = self.cache.get(key).await;
+        let outcome = if hit.is_some() { "hit" } else { "miss" };
+        metrics::counter!("renderer_output_cache_requests_total", "route" => route, "outcome" => outcome).increment(1);
+        hit
+    }
+
+    pub async fn insert(&self, key: String, content_type: &'static str, bytes: Vec<u8>) -> Arc<CachedRender> {
+        let render = Arc::new(CachedRender {
+            content_type,
+            etag: key.clone(),
+            bytes: Bytes::from(bytes),
+        });
+        self.cache.insert(key, render.clone()).await;
+        render
+    }
+}
+
+/// Decoded remote images (avatars, role and clan icons, custom emoji, theme
+/// backgrounds), each already resized to the box it is drawn in, keyed by URL
+/// and box. A 1024px avatar shown on a leaderboard row costs 57×57×4 bytes, and
+/// the cache is bounded by those pixel bytes rather than an entry count.
+///
+/// Loads are single-flight: concurrent renders that need the same URL and box
+/// wait on one load, and a fetched body is shared for a few seconds by every box
+/// it is decoded into. A URL that fails to load is remembered for the negative
+/// TTL, so a dead avatar costs one fetch rather than one per render.
+pub struct AvatarCache {
+    images: Cache<(String, ImageBox), Arc<Pixmap>>,
+    bodies: Cache<String, Bytes>,
+    /// Failed URLs and the reason they failed.
+    failures: Cache<String, &'static str>,
+}
+
+impl AvatarCache {
+    pub fn new(max_bytes: u64, ttl: Duration, negative_ttl: Duration) -> Self {
+        let images = Cache::builder()
+            .weigher(|_key: &(String, ImageBox), value: &Arc<Pixmap>| {
+                value.data().len().try_into().unwrap_or(u32::MAX)
+            })
+            .max_capacity(max_bytes)
+            .time_to_live(ttl)
+            .eviction_listener(|_key, _value, cause| {
+                let cause = match cause {
+                    moka::notification::RemovalCause::Size => "size",
+                    moka::notification::RemovalCause::Expired => "expired",
+                    _ => return,
+                };
+                metrics::counter!("renderer_avatar_cache_evictions_total", "cause" => cause).increment(1);
+            })
+            .build();
+        let bodies = Cache::builder()
+            .weigher(|_key: &String, value: &Bytes| value.len().try_into().unwrap_or(u32::MAX))
+            .max_capacity(BODY_CACHE_MAX_BYTES)
+            .time_to_live(BODY_CACHE_TTL)
+            .build();
+        let failures = Cache::builder().max_capacity(16 * 1024).time_to_live(negative_ttl).build();
+        Self { images, bodies, failures }
+    }
+
+    /// `AVATAR_CACHE_MAX_BYTES` (default 64 MiB), `AVATAR_CACHE_TTL_SECS` (default 900)
+    /// and `AVATAR_NEGATIVE_TTL_SECS` (default 60).
+    pub fn from_env() -> Self {
+        let max_bytes = std::env::var("AVATAR_CACHE_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(64 * 1024 * 1024);
+        let ttl_secs = std::env::var("AVATAR_CACHE_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(15 * 60);
+        let negative_ttl_secs = std::env::var("AVATAR_NEGATIVE_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60);
+        Self::new(max_bytes, Duration::from_secs(ttl_secs), Duration::from_secs(negative_ttl_secs))
+    }
+
+    /// The image at `url` sized for `target`, calling `fetch` for its body on a
+    /// miss. `None` if the URL failed recently, or fails now, to fetch or decode.
+    pub async fn get_or_fetch<F, Fut>(&self, url: &str, target: ImageBox, fetch: F) -> Option<Arc<Pixmap>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Bytes, FetchError>>,
+    {
+        if let Some(reason) = self.failures.get(url).await {
+            metrics::counter!("renderer_avatar_cache_requests_total", "outcome" => "negative").increment(1);
+            tracing::debug!("Skipping {}, failed recently ({})", url, reason);
+            return None;
+        }
+
+        let load = async {
+            let bytes = self.bodies.try_get_with(url.to_string(), fetch()).await?;
+            // Decoding and resampling a large image takes milliseconds of CPU,
+            // so it runs on the blocking pool rather than a runtime worker.
+            tokio::task::spawn_blocking(move || decode_image_for(&bytes, target))
+                .await
+                .ok()
+                .flatten()
+                .map(Arc::new)
+                .ok_or_else(|| Arc::new(FetchError::NotImage))
+        };
+        match self.images.entry((url.to_string(), target)).or_try_insert_with(load).await {
+            Ok(entry) => {
+                let outcome = if entry.is_fresh() { "miss" } else { "hit" };
+                metrics::counter!("renderer_avatar_cache_requests_total", "outcome" => outcome).increment(1);
+                if entry.is_fresh() {
+                    // Inserts follow a network fetch, so settling the size here is cheap by comparison.
+                    self.images.run_pending_tasks().await;
+                    metrics::gauge!("renderer_avatar_cache_bytes").set(self.images.weighted_size() as f64);
+                }
+                Some(entry.into_value())
+            }
+            Err(e) => {
+                metrics::counter!("renderer_avatar_cache_requests_total", "outcome" => "failed").increment(1);
+                self.failures.insert(url.to_string(), e.reason()).await;
+                None
+            }
+        }
+    }
+}
+
+/// Short stable hash of any serializable value (e.g. a rank card theme).
+pub fn hash_json<T: Serialize>(value: &T) -> String {
+    let digest = Sha256::digest(serde_json::to_vec(value).unwrap_or_default());
+    format!("{:x}", digest)[..16].to_string()
+}
+
+/// Hashes the raw sources of every template in effect together with the crate
+/// version, so any template edit invalidates cached renders and ETags.
+pub fn template_version(sources: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    for source in sources {
+        hasher.update(source.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
+
+/// True if `If-None-Match` lists `etag`. `*` is not honoured: it only asks
+/// whether some representation exists, which says nothing about a POST body.
+pub fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag)
+}
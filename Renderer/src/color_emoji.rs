@@ -0,0 +1,244 @@
+use moka::future::Cache;
+use rustybuzz::ttf_parser::{self, colr, GlyphId, OutlineBuilder, RasterImageFormat, RgbaColor};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tiny_skia::Pixmap;
+use usvg::{TreeParsing, TreePostProc};
+
+use crate::render::decode_image;
+
+/// Size emoji glyphs are rasterized at; inline emoji are drawn at 30–42 px, so
+/// this leaves headroom for the 3041×894 role reward canvas.
+const GLYPH_PX: u32 = 72;
+
+/// Renders emoji that appear directly in text from a colour emoji font, since
+/// usvg only draws glyph outlines and would fall back to a monochrome face.
+///
+/// Bitmap fonts (`sbix`, `CBDT`, e.g. Noto Color Emoji) have their embedded PNG
+/// used as-is; `COLR` v0 fonts (e.g. Twemoji COLR builds) have their layers
+/// painted with the `CPAL` palette. Each cluster is shaped through the font's
+/// own GSUB, so ZWJ sequences, skin tones, flags and keycaps resolve to the
+/// single glyph the font draws for them.
+pub struct ColorEmojiFont {
+    data: Arc<Vec<u8>>,
+    /// Rasterized glyph per cluster; `None` records a cluster the font has no glyph for.
+    glyphs: Cache<String, Option<Arc<Pixmap>>>,
+}
+
+impl ColorEmojiFont {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        let face = ttf_parser::Face::parse(&data, 0)?;
+        let tables = face.tables();
+        if tables.sbix.is_none() && tables.cbdt.is_none() && tables.colr.is_none() {
+            anyhow::bail!("{} has no sbix, CBDT or COLR table", path.display());
+        }
+        Ok(Self {
+            data: Arc::new(data),
+            glyphs: Cache::builder().max_capacity(4096).build(),
+        })
+    }
+
+    /// `COLOR_EMOJI_FONT`, else `assets/fonts/NotoColorEmoji.ttf`, else a known
+    /// colour emoji font in the system font directories (as installed by e.g.
+    /// `fonts-noto-color-emoji`). No font ships with the renderer; without one,
+    /// emoji in text keep rendering through regular (monochrome) font fallback.
+    pub fn from_env() -> Option<Self> {
+        let path = match std::env::var("COLOR_EMOJI_FONT") {
+            Ok(path) if !path.is_empty() => PathBuf::from(path),
+            _ => match find_font() {
+                Some(path) => path,
+                None => {
+                    tracing::warn!(
+                        "No colour emoji font found, emoji in text will be monochrome; \
+                         install fonts-noto-color-emoji or set COLOR_EMOJI_FONT"
+                    );
+                    return None;
+                }
+            },
+        };
+        match Self::load(&path) {
+            Ok(font) => {
+                tracing::info!("Loaded colour emoji font {}", path.display());
+                Some(font)
+            }
+            Err(e) => {
+                tracing::warn!("Colour emoji font unusable, emoji in text will be monochrome: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Image of the emoji `cluster` (one extended grapheme), or `None` if the
+    /// font does not draw it as a single colour glyph.
+    pub async fn render(&self, cluster: &str) -> Option<Arc<Pixmap>> {
+        if let Some(glyph) = self.glyphs.get(cluster).await {
+            return glyph;
+        }
+        // Shaping and painting a glyph is CPU work, so it stays off the runtime workers.
+        let data = self.data.clone();
+        let owned = cluster.to_string();
+        let glyph = tokio::task::spawn_blocking(move || rasterize(&data, &owned))
+            .await
+            .ok()
+            .flatten()
+            .map(Arc::new);
+        if glyph.is_none() {
+            metrics::counter!("renderer_color_emoji_missing_total").increment(1);
+        }
+        self.glyphs.insert(cluster.to_string(), glyph.clone()).await;
+        glyph
+    }
+}
+
+/// Shapes `cluster` with the font in `data` and rasterizes the single colour
+/// glyph it produces.
+fn rasterize(data: &[u8], cluster: &str) -> Option<Pixmap> {
+    let face = rustybuzz::Face::from_slice(data, 0)?;
+    let mut buffer = rustybuzz::UnicodeBuffer::new();
+    buffer.push_str(cluster);
+    let output = rustybuzz::shape(&face, &[], buffer);
+
+    // Variation selectors and joiners may survive shaping as empty glyphs;
+    // the cluster is drawable only if exactly one glyph carries colour.
+    let mut drawable = output
+        .glyph_infos()
+        .iter()
+        .map(|info| GlyphId(info.glyph_id as u16))
+        .filter(|&id| id.0 != 0 && (face.is_color_glyph(id) || face.glyph_raster_image(id, GLYPH_PX as u16).is_some()));
+    let glyph = drawable.next()?;
+    if drawable.next().is_some() {
+        return None;
+    }
+
+    if let Some(image) = face.glyph_raster_image(glyph, GLYPH_PX as u16) {
+        return (image.format == RasterImageFormat::PNG).then(|| decode_image(image.data)).flatten();
+    }
+    paint_colr(&face, glyph)
+}
+
+/// Colour emoji fonts looked for by file name, in order of preference. Both
+/// are formats `load` accepts (CBDT and COLR v0 respectively).
+const FONT_FILES: &[&str] = &["NotoColorEmoji.ttf", "Twemoji.Mozilla.ttf"];
+
+/// The first of `FONT_FILES` in the asset font directory, then in the usual
+/// system font directories.
+fn find_font() -> Option<PathBuf> {
+    let mut dirs = vec![PathBuf::from("./assets/fonts"), PathBuf::from("../assets/fonts")];
+    dirs.extend(["/usr/share/fonts", "/usr/local/share/fonts"].map(PathBuf::from));
+    if let Some(home) = std::env::var_os("HOME") {
+        dirs.push(Path::new(&home).join(".local/share/fonts"));
+        dirs.push(Path::new(&home).join(".fonts"));
+    }
+    FONT_FILES.iter().find_map(|name| dirs.iter().find_map(|dir| find_file(dir, name, 4)))
+}
+
+/// `name` in `dir` or its subdirectories, at most `depth` levels down.
+fn find_file(dir: &Path, name: &str, depth: usize) -> Option<PathBuf> {
+    let path = dir.join(name);
+    if path.is_file() {
+        return Some(path);
+    }
+    if depth == 0 {
+        return None;
+    }
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .find_map(|path| find_file(&path, name, depth - 1))
+}
+
+/// True for grapheme clusters that are presented as emoji: pictographs, flags,
+/// keycaps, and anything carrying the emoji variation selector or a ZWJ.
+pub fn is_emoji_cluster(cluster: &str) -> bool {
+    cluster.chars().any(|c| matches!(c, '\u{FE0F}' | '\u{200D}' | '\u{20E3}'))
+        || cluster.chars().next().is_some_and(|c| {
+            matches!(c as u32, 0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2B00..=0x2BFF | 0x231A..=0x23FF)
+        })
+}
+
+/// Paints a `COLR` v0 glyph's layers into an SVG and rasterizes it.
+fn paint_colr(face: &ttf_parser::Face, glyph: GlyphId) -> Option<Pixmap> {
+    let mut painter = SvgPainter { face, path: String::new(), svg: String::new() };
+    face.paint_color_glyph(glyph, 0, &mut painter)?;
+
+    let units = face.units_per_em() as f32;
+    let ascender = face.ascender() as f32;
+    let svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{px}" height="{px}" viewBox="0 0 {units} {units}"><g transform="matrix(1 0 0 -1 0 {ascender})">{}</g></svg>"#,
+        painter.svg,
+        px = GLYPH_PX,
+    );
+
+    let mut tree = usvg::Tree::from_str(&svg, &usvg::Options::default()).ok()?;
+    tree.postprocess(usvg::PostProcessingSteps::default(), &usvg::fontdb::Database::new());
+    let mut pixmap = Pixmap::new(GLYPH_PX, GLYPH_PX)?;
+    resvg::render(&tree, usvg::Transform::default(), &mut pixmap.as_mut());
+    Some(pixmap)
+}
+
+/// Collects `COLR` layers as SVG `<path>` elements in font units.
+struct SvgPainter<'a> {
+    face: &'a ttf_parser::Face<'a>,
+    path: String,
+    svg: String,
+}
+
+impl SvgPainter<'_> {
+    fn fill(&mut self, color: RgbaColor) {
+        let _ = write!(
+            self.svg,
+            r##"<path d="{}" fill="#{:02x}{:02x}{:02x}" fill-opacity="{}"/>"##,
+            self.path,
+            color.red,
+            color.green,
+            color.blue,
+            color.alpha as f32 / 255.0
+        );
+    }
+}
+
+impl colr::Painter for SvgPainter<'_> {
+    fn outline(&mut self, glyph_id: GlyphId) {
+        let mut path = SvgPath(String::new());
+        self.path = match self.face.outline_glyph(glyph_id, &mut path) {
+            Some(_) => path.0,
+            None => String::new(),
+        };
+    }
+
+    fn paint_foreground(&mut self) {
+        self.fill(RgbaColor::new(0, 0, 0, 255));
+    }
+
+    fn paint_color(&mut self, color: RgbaColor) {
+        self.fill(color);
+    }
+}
+
+struct SvgPath(String);
+
+impl OutlineBuilder for SvgPath {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let _ = write!(self.0, "M{} {}", x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let _ = write!(self.0, "L{} {}", x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let _ = write!(self.0, "Q{} {} {} {}", x1, y1, x, y);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let _ = write!(self.0, "C{} {} {} {} {} {}", x1, y1, x2, y2, x, y);
+    }
+
+    fn close(&mut self) {
+        self.0.push('Z');
+    }
+}
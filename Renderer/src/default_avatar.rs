@@ -0,0 +1,144 @@
+use moka::future::Cache;
+use std::sync::Arc;
+use tiny_skia::Pixmap;
+use unicode_segmentation::UnicodeSegmentation;
+use usvg::fontdb::Database;
+use usvg::{TreeParsing, TreePostProc};
+
+/// Background colours of Discord's default avatars, in index order: blurple,
+/// grey, green, yellow, red and, for accounts on the new username system, pink.
+const COLORS: &[&str] = &["#5865F2", "#757E8A", "#3BA55C", "#FAA61A", "#ED4245", "#EB459E"];
+
+/// The Discord mark, 127.14×96.36 user units, drawn white on the colour.
+const CLYDE_PATH: &str = "M107.7,8.07A105.15,105.15,0,0,0,81.47,0a72.06,72.06,0,0,0-3.36,6.83A97.68,97.68,0,0,0,49,6.83,72.37,72.37,0,0,0,45.64,0,105.89,105.89,0,0,0,19.39,8.09C2.79,32.65-1.71,56.6.54,80.21h0A105.73,105.73,0,0,0,32.71,96.36,77.7,77.7,0,0,0,39.6,85.25a68.42,68.42,0,0,1-10.85-5.18c.91-.66,1.8-1.34,2.66-2a75.57,75.57,0,0,0,64.32,0c.87.71,1.76,1.39,2.66,2a68.68,68.68,0,0,1-10.87,5.19,77,77,0,0,0,6.89,11.1A105.25,105.25,0,0,0,126.6,80.22h0C129.24,52.84,122.09,29.11,107.7,8.07ZM42.45,65.69C36.18,65.69,31,60,31,53s5-12.74,11.43-12.74S54,46,53.89,53,48.84,65.69,42.45,65.69Zm42.24,0C78.41,65.69,73.25,60,73.25,53s5-12.74,11.44-12.74S96.23,46,96.12,53,91.08,65.69,84.69,65.69Z";
+
+/// Largest side a default avatar is drawn at; bigger boxes scale it up.
+const MAX_SIZE: u32 = 600;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AvatarStyle {
+    /// Discord's own default: the Discord mark on one of its avatar colours.
+    Discord,
+    /// Up to two initials of the name on the same colour.
+    Initials,
+}
+
+/// Who a default avatar is for. The colour follows Discord's rule: a legacy
+/// non-zero discriminator picks `discriminator % 5`, otherwise the snowflake
+/// picks `(user_id >> 22) % 6`. Without either, a hash of the name decides.
+pub struct AvatarSeed<'a> {
+    pub user_id: Option<&'a str>,
+    pub discriminator: Option<&'a str>,
+    pub name: &'a str,
+}
+
+impl AvatarSeed<'_> {
+    fn index(&self) -> usize {
+        let discriminator = self.discriminator.and_then(|d| d.parse::<u64>().ok()).filter(|&d| d != 0);
+        if let Some(discriminator) = discriminator {
+            return (discriminator % 5) as usize;
+        }
+        if let Some(id) = self.user_id.and_then(|id| id.parse::<u64>().ok()) {
+            return ((id >> 22) % 6) as usize;
+        }
+        let hash = self.name.bytes().fold(0u32, |h, b| h.wrapping_mul(31).wrapping_add(b as u32));
+        (hash % 5) as usize
+    }
+}
+
+/// Generates the avatar drawn wherever a user's avatar URL is empty or fails
+/// to load, so every route shows the same placeholder for the same user.
+/// Images are drawn at the size they are shown and kept in a small LRU.
+pub struct DefaultAvatars {
+    style: AvatarStyle,
+    db: Arc<Database>,
+    /// Keyed by colour index, initials (empty for the Discord style) and size.
+    rendered: Cache<(usize, String, u32), Arc<Pixmap>>,
+}
+
+impl DefaultAvatars {
+    pub fn new(style: AvatarStyle, db: Arc<Database>) -> Self {
+        Self {
+            style,
+            db,
+            rendered: Cache::builder().max_capacity(1024).build(),
+        }
+    }
+
+    /// `DEFAULT_AVATAR_STYLE`: `discord` (default) or `initials`.
+    pub fn from_env(db: Arc<Database>) -> Self {
+        let style = match std::env::var("DEFAULT_AVATAR_STYLE").as_deref() {
+            Ok("initials") => AvatarStyle::Initials,
+            _ => AvatarStyle::Discord,
+        };
+        Self::new(style, db)
+    }
+
+    /// Default avatar for `seed`, `size` pixels square (capped at `MAX_SIZE`).
+    pub async fn get(&self, seed: &AvatarSeed<'_>, size: u32) -> Arc<Pixmap> {
+        let index = seed.index();
+        let initials = match self.style {
+            AvatarStyle::Discord => String::new(),
+            AvatarStyle::Initials => initials(seed.name),
+        };
+        let size = size.clamp(1, MAX_SIZE);
+        self.rendered
+            .get_with((index, initials.clone(), size), async {
+                metrics::counter!("renderer_default_avatars_rendered_total").increment(1);
+                // Rasterising is CPU work, so it stays off the runtime workers.
+                let db = self.db.clone();
+                let rendered = tokio::task::spawn_blocking(move || render(&db, index, &initials, size)).await;
+                Arc::new(rendered.unwrap_or_else(|e| {
+                    tracing::warn!("Default avatar render failed: {}", e);
+                    Pixmap::new(size, size).expect("default avatar size is non-zero")
+                }))
+            })
+            .await
+    }
+}
+
+fn render(db: &Database, index: usize, initials: &str, size: u32) -> Pixmap {
+    let color = COLORS[index % COLORS.len()];
+    let mark = if initials.is_empty() {
+        // Centred at 60% of the width, as on Discord's own default avatars.
+        format!(
+            r##"<path transform="translate(51.2 69.8) scale(1.208)" d="{}" fill="#ffffff"/>"##,
+            CLYDE_PATH
+        )
+    } else {
+        format!(
+            r##"<text x="128" y="128" dominant-baseline="central" text-anchor="middle" font-family="Poppins, DejaVu Sans, sans-serif" font-size="{}" font-weight="bold" fill="#ffffff">{}</text>"##,
+            if initials.chars().count() > 1 { 104 } else { 128 },
+            escape_xml(initials)
+        )
+    };
+    let svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 256 256"><rect width="256" height="256" fill="{}"/>{}</svg>"#,
+        color, mark,
+    );
+
+    // `get` clamps the size to 1..=MAX_SIZE.
+    let mut pixmap = Pixmap::new(size, size).expect("default avatar size is non-zero");
+    match usvg::Tree::from_str(&svg, &usvg::Options::default()) {
+        Ok(mut tree) => {
+            tree.postprocess(usvg::PostProcessingSteps::default(), db);
+            resvg::render(&tree, usvg::Transform::default(), &mut pixmap.as_mut());
+        }
+        Err(e) => tracing::warn!("Default avatar SVG failed to parse: {}", e),
+    }
+    pixmap
+}
+
+/// First grapheme of the first two words, upper-cased, e.g. "ada lovelace" → "AL".
+fn initials(name: &str) -> String {
+    let initials: String = name
+        .split_whitespace()
+        .filter_map(|word| word.graphemes(true).next())
+        .take(2)
+        .collect();
+    if initials.is_empty() { "?".to_string() } else { initials.to_uppercase() }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
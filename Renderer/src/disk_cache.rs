@@ -0,0 +1,315 @@
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// First bytes of every entry; bump the digit if the layout below changes so
+/// old files read as corrupt and are dropped.
+const MAGIC: &[u8; 4] = b"RDC1";
+/// Magic, expiry (unix seconds), body length, body SHA-256, URL length.
+const HEADER_LEN: usize = 4 + 8 + 8 + 32 + 4;
+const ENTRY_EXT: &str = "img";
+
+/// Second-tier cache of fetched image bodies (avatars, role and clan icons,
+/// backgrounds), kept on disk so a restart does not send every avatar of the
+/// first leaderboard back to the CDN.
+///
+/// Entries hold the body exactly as fetched, so every size `AvatarCache` needs
+/// is decoded from one file. Each file carries its URL, an expiry and a
+/// checksum of the body; an expired, truncated or mismatching entry is deleted
+/// and treated as a miss. The total size is bounded, evicting the least
+/// recently used entries first. Files are written to a temporary name and
+/// renamed, so a crash mid-write never leaves a half entry behind.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    index: Mutex<Index>,
+    temp_seq: AtomicU64,
+}
+
+/// Size and last use of every entry file, rebuilt from the directory at startup.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, IndexEntry>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+struct IndexEntry {
+    bytes: u64,
+    last_used: u64,
+}
+
+impl Index {
+    fn touch(&mut self, name: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(name) {
+            entry.last_used = self.clock;
+        }
+    }
+
+    fn insert(&mut self, name: String, bytes: u64) {
+        self.clock += 1;
+        let previous = self.entries.insert(name, IndexEntry { bytes, last_used: self.clock });
+        self.total_bytes = self.total_bytes - previous.map_or(0, |e| e.bytes) + bytes;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.total_bytes -= entry.bytes;
+        }
+    }
+
+    /// Drops least recently used entries until `max_bytes` fits, returning their names.
+    fn evict_to(&mut self, max_bytes: u64) -> Vec<String> {
+        if self.total_bytes <= max_bytes {
+            return Vec::new();
+        }
+        let mut by_age: Vec<(u64, String)> =
+            self.entries.iter().map(|(name, e)| (e.last_used, name.clone())).collect();
+        by_age.sort_unstable();
+        let mut evicted = Vec::new();
+        for (_, name) in by_age {
+            if self.total_bytes <= max_bytes {
+                break;
+            }
+            self.remove(&name);
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+impl DiskCache {
+    /// Opens (creating if needed) `dir` and indexes the entries already in it,
+    /// oldest modification first, then trims the index to `max_bytes`.
+    pub fn open(dir: &Path, max_bytes: u64, ttl: Duration) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            let path = entry.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()).map(str::to_string) else { continue };
+            if name.starts_with(".tmp-") {
+                // Left over from a write interrupted by a crash.
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            if path.extension().and_then(|e| e.to_str()) != Some(ENTRY_EXT) {
+                continue;
+            }
+            let Ok(meta) = entry.metadata() else { continue };
+            found.push((meta.modified().unwrap_or(UNIX_EPOCH), name, meta.len()));
+        }
+        found.sort();
+
+        let mut index = Index::default();
+        for (_, name, bytes) in found {
+            index.insert(name, bytes);
+        }
+        for name in index.evict_to(max_bytes) {
+            let _ = std::fs::remove_file(dir.join(name));
+        }
+
+        tracing::info!(
+            "Disk cache {}: {} entries, {} bytes (max {})",
+            dir.display(),
+            index.entries.len(),
+            index.total_bytes,
+            max_bytes
+        );
+        metrics::gauge!("renderer_disk_cache_bytes").set(index.total_bytes as f64);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            ttl,
+            index: Mutex::new(index),
+            temp_seq: AtomicU64::new(0),
+        })
+    }
+
+    /// `DISK_CACHE_DIR` (unset or empty disables the cache, e.g. `/dev/shm/renderer`),
+    /// `DISK_CACHE_MAX_BYTES` (default 512 MiB) and `DISK_CACHE_TTL_SECS` (default 86400).
+    pub fn from_env() -> Option<Self> {
+        let dir = match std::env::var("DISK_CACHE_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => return None,
+        };
+        let max_bytes = std::env::var("DISK_CACHE_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(512 * 1024 * 1024);
+        let ttl_secs = std::env::var("DISK_CACHE_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(24 * 60 * 60);
+        match Self::open(&dir, max_bytes, Duration::from_secs(ttl_secs)) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                tracing::warn!("Disk cache {} unusable, fetching without it: {}", dir.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Body cached for `url`, if present, unexpired and intact.
+    pub async fn get(&self, url: &str) -> Option<Bytes> {
+        let name = entry_name(url);
+        if !self.lock().entries.contains_key(&name) {
+            metrics::counter!("renderer_disk_cache_requests_total", "outcome" => "miss").increment(1);
+            return None;
+        }
+
+        let outcome = match tokio::fs::read(self.dir.join(&name)).await {
+            Ok(file) => match parse_entry(&file, url) {
+                Some((expires_at, body)) if expires_at > unix_now() => {
+                    self.lock().touch(&name);
+                    metrics::counter!("renderer_disk_cache_requests_total", "outcome" => "hit").increment(1);
+                    return Some(Bytes::copy_from_slice(body));
+                }
+                Some(_) => "expired",
+                None => {
+                    tracing::warn!("Dropping corrupt disk cache entry {} for {}", name, url);
+                    "corrupt"
+                }
+            },
+            Err(_) => "miss",
+        };
+        metrics::counter!("renderer_disk_cache_requests_total", "outcome" => outcome).increment(1);
+        self.remove(&name).await;
+        None
+    }
+
+    /// Stores `body` for `url`. Failures are logged and otherwise ignored; the
+    /// cache is only ever an optimisation.
+    pub async fn insert(&self, url: &str, body: &[u8]) {
+        let name = entry_name(url);
+        let file = encode_entry(url, body, unix_now() + self.ttl.as_secs());
+        if file.len() as u64 > self.max_bytes {
+            return;
+        }
+
+        let temp = self.dir.join(format!(".tmp-{}-{}", name, self.temp_seq.fetch_add(1, Ordering::Relaxed)));
+        let written = match tokio::fs::write(&temp, &file).await {
+            Ok(()) => tokio::fs::rename(&temp, self.dir.join(&name)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            tracing::warn!("Disk cache write failed for {}: {}", url, e);
+            let _ = tokio::fs::remove_file(&temp).await;
+            return;
+        }
+
+        let evicted = {
+            let mut index = self.lock();
+            index.insert(name, file.len() as u64);
+            let evicted = index.evict_to(self.max_bytes);
+            metrics::gauge!("renderer_disk_cache_bytes").set(index.total_bytes as f64);
+            evicted
+        };
+        if !evicted.is_empty() {
+            metrics::counter!("renderer_disk_cache_evictions_total").increment(evicted.len() as u64);
+        }
+        for name in evicted {
+            let _ = tokio::fs::remove_file(self.dir.join(name)).await;
+        }
+    }
+
+    async fn remove(&self, name: &str) {
+        {
+            let mut index = self.lock();
+            index.remove(name);
+            metrics::gauge!("renderer_disk_cache_bytes").set(index.total_bytes as f64);
+        }
+        let _ = tokio::fs::remove_file(self.dir.join(name)).await;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn entry_name(url: &str) -> String {
+    let digest = Sha256::digest(url.as_bytes());
+    format!("{:x}.{}", digest, ENTRY_EXT)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn encode_entry(url: &str, body: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut file = Vec::with_capacity(HEADER_LEN + url.len() + body.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&expires_at.to_le_bytes());
+    file.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    file.extend_from_slice(&Sha256::digest(body));
+    file.extend_from_slice(&(url.len() as u32).to_le_bytes());
+    file.extend_from_slice(url.as_bytes());
+    file.extend_from_slice(body);
+    file
+}
+
+/// Expiry and body of an entry file, or `None` if it is not a well-formed
+/// entry for `url` whose body matches its checksum.
+fn parse_entry<'a>(file: &'a [u8], url: &str) -> Option<(u64, &'a [u8])> {
+    let header = file.get(..HEADER_LEN)?;
+    if &header[..4] != MAGIC {
+        return None;
+    }
+    let expires_at = u64::from_le_bytes(header[4..12].try_into().ok()?);
+    let body_len = usize::try_from(u64::from_le_bytes(header[12..20].try_into().ok()?)).ok()?;
+    let checksum = &header[20..52];
+    let url_len = u32::from_le_bytes(header[52..56].try_into().ok()?) as usize;
+
+    let rest = &file[HEADER_LEN..];
+    if rest.len() != url_len.checked_add(body_len)? || &rest[..url_len] != url.as_bytes() {
+        return None;
+    }
+    let body = &rest[url_len..];
+    (Sha256::digest(body).as_slice() == checksum).then_some((expires_at, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://cdn.discordapp.com/avatars/1/a.png";
+
+    #[test]
+    fn entry_round_trips() {
+        let file = encode_entry(URL, b"image body", 1234);
+        assert_eq!(parse_entry(&file, URL), Some((1234, &b"image body"[..])));
+    }
+
+    #[test]
+    fn damaged_or_foreign_entries_are_rejected() {
+        let file = encode_entry(URL, b"image body", 1234);
+        assert_eq!(parse_entry(&file[..file.len() - 1], URL), None);
+        assert_eq!(parse_entry(&file[..HEADER_LEN - 1], URL), None);
+        assert_eq!(parse_entry(&file, "https://cdn.discordapp.com/avatars/2/a.png"), None);
+
+        let mut corrupt = file.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(parse_entry(&corrupt, URL), None);
+
+        let mut old_layout = file;
+        old_layout[3] = b'0';
+        assert_eq!(parse_entry(&old_layout, URL), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let mut index = Index::default();
+        index.insert("a".into(), 10);
+        index.insert("b".into(), 20);
+        index.insert("c".into(), 30);
+        index.touch("a");
+        index.insert("b".into(), 25);
+        assert_eq!(index.total_bytes, 65);
+
+        assert!(index.evict_to(65).is_empty());
+        assert_eq!(index.evict_to(40), vec!["c".to_string()]);
+        assert_eq!(index.total_bytes, 35);
+        assert_eq!(index.evict_to(0), vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(index.total_bytes, 0);
+    }
+}
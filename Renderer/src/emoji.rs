@@ -0,0 +1,167 @@
+use moka::future::Cache;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tiny_skia::Pixmap;
+use usvg::{TreeParsing, TreePostProc};
+
+use crate::render::decode_image;
+
+/// Emoji assets are 72×72 Twemoji-style PNGs; the fallback glyph matches.
+const EMOJI_SIZE: u32 = 72;
+
+/// Drawn for a hex with no asset: a rounded "tofu" box with a question mark,
+/// built from paths so it needs no font. Override with `fallback.png`.
+const FALLBACK_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="72" height="72" viewBox="0 0 72 72">
+  <rect x="6" y="6" width="60" height="60" rx="12" fill="#4f545c" stroke="#b9bbbe" stroke-width="4"/>
+  <path d="M27 28a9 9 0 1 1 13.5 7.8c-2.9 1.7-4.5 3.2-4.5 6.2v2" fill="none" stroke="#ffffff" stroke-width="6" stroke-linecap="round"/>
+  <circle cx="36" cy="54" r="3.5" fill="#ffffff"/>
+</svg>"##;
+
+/// In-memory emoji atlas over `assets/emojis/<hex>.png`.
+///
+/// The directory is indexed at startup, so a known hex costs a map lookup.
+/// The bot downloads emoji into the same directory as names use them, so a hex
+/// missing from the index is looked for on disk and indexed once found. Each
+/// asset is read and decoded the first time it is used and then served from an
+/// LRU-bounded cache of pixmaps. Hexes with no asset resolve to a fallback glyph.
+pub struct EmojiStore {
+    dir: PathBuf,
+    index: RwLock<HashMap<String, PathBuf>>,
+    decoded: Cache<String, Arc<Pixmap>>,
+    fallback: Arc<Pixmap>,
+}
+
+impl EmojiStore {
+    pub fn load(dir: &Path, capacity: u64) -> anyhow::Result<Self> {
+        let mut index = HashMap::new();
+        match std::fs::read_dir(dir) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+                        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                            index.insert(stem.to_ascii_lowercase(), path);
+                        }
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("Emoji directory {} unreadable: {}", dir.display(), e),
+        }
+
+        let fallback = match index.remove("fallback") {
+            Some(path) => std::fs::read(&path).ok().and_then(|bytes| decode_image(&bytes)),
+            None => None,
+        };
+        let fallback = match fallback {
+            Some(pixmap) => pixmap,
+            None => render_fallback()?,
+        };
+
+        tracing::info!("Indexed {} emoji assets in {}", index.len(), dir.display());
+        metrics::gauge!("renderer_emoji_assets_indexed").set(index.len() as f64);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index: RwLock::new(index),
+            decoded: Cache::builder().max_capacity(capacity).build(),
+            fallback: Arc::new(fallback),
+        })
+    }
+
+    /// `EMOJI_DIR` (default `./assets/emojis`, then `../assets/emojis`) and
+    /// `EMOJI_CACHE_CAPACITY` (default 4096 emoji).
+    pub fn from_env() -> anyhow::Result<Self> {
+        let dir = match std::env::var("EMOJI_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ if Path::new("./assets/emojis").exists() => PathBuf::from("./assets/emojis"),
+            _ => PathBuf::from("../assets/emojis"),
+        };
+        let capacity = std::env::var("EMOJI_CACHE_CAPACITY").ok().and_then(|v| v.parse().ok()).unwrap_or(4096);
+        Self::load(&dir, capacity)
+    }
+
+    /// Decoded image for `hex`, or `None` if there is no such asset. Hexes are
+    /// matched case-insensitively, and with and without the U+FE0F variation
+    /// selector, since callers and asset sets disagree on whether to include it.
+    pub async fn get(&self, hex: &str) -> Option<Arc<Pixmap>> {
+        let hex = hex.to_ascii_lowercase();
+        let stripped = strip_variation_selectors(&hex);
+        let candidates = [hex.as_str(), stripped.as_str()];
+        let indexed = {
+            let index = self.index.read().unwrap();
+            candidates
+                .iter()
+                .find_map(|candidate| index.get_key_value(*candidate))
+                .map(|(key, path)| (key.clone(), path.clone()))
+        };
+        let (key, path) = match indexed {
+            Some(entry) => entry,
+            None => self.discover(&candidates).await?,
+        };
+
+        if let Some(pixmap) = self.decoded.get(&key).await {
+            return Some(pixmap);
+        }
+        let pixmap = Arc::new(decode_image(&tokio::fs::read(&path).await.ok()?)?);
+        self.decoded.insert(key, pixmap.clone()).await;
+        Some(pixmap)
+    }
+
+    /// Looks on disk for an asset added since startup and indexes it.
+    async fn discover(&self, candidates: &[&str]) -> Option<(String, PathBuf)> {
+        for candidate in candidates {
+            if candidate.is_empty() || *candidate == "fallback" || !candidate.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+                continue;
+            }
+            let path = self.dir.join(format!("{}.png", candidate));
+            if tokio::fs::metadata(&path).await.is_ok_and(|meta| meta.is_file()) {
+                let mut index = self.index.write().unwrap();
+                index.insert(candidate.to_string(), path.clone());
+                metrics::gauge!("renderer_emoji_assets_indexed").set(index.len() as f64);
+                return Some((candidate.to_string(), path));
+            }
+        }
+        None
+    }
+
+    /// Like `get`, but an unknown hex yields the fallback glyph and is counted.
+    /// The flag is set when the glyph is the fallback, so callers can tell a
+    /// placeholder from the real emoji. A hex made only of variation selectors
+    /// and joiners yields `None`: those code points have no picture of their
+    /// own and are not drawn at all.
+    pub async fn get_or_fallback(&self, hex: &str) -> Option<(Arc<Pixmap>, bool)> {
+        if draws_nothing(hex) {
+            return None;
+        }
+        match self.get(hex).await {
+            Some(pixmap) => Some((pixmap, false)),
+            None => {
+                metrics::counter!("renderer_emoji_unknown_total").increment(1);
+                tracing::debug!("No emoji asset for {}, using fallback glyph", hex);
+                Some((self.fallback.clone(), true))
+            }
+        }
+    }
+}
+
+/// True for a sequence of only U+FE0E/U+FE0F variation selectors and U+200D
+/// zero-width joiners, which the bot sends as entries of their own.
+fn draws_nothing(hex: &str) -> bool {
+    hex.split('-').all(|cp| {
+        let cp = cp.trim_start_matches('0');
+        cp.eq_ignore_ascii_case("fe0e") || cp.eq_ignore_ascii_case("fe0f") || cp == "200d"
+    })
+}
+
+fn strip_variation_selectors(hex: &str) -> String {
+    hex.split('-').filter(|cp| *cp != "fe0f").collect::<Vec<_>>().join("-")
+}
+
+fn render_fallback() -> anyhow::Result<Pixmap> {
+    let mut tree = usvg::Tree::from_str(FALLBACK_SVG, &usvg::Options::default())?;
+    tree.postprocess(usvg::PostProcessingSteps::default(), &usvg::fontdb::Database::new());
+    let mut pixmap = tiny_skia::Pixmap::new(EMOJI_SIZE, EMOJI_SIZE)
+        .ok_or_else(|| anyhow::anyhow!("cannot allocate fallback emoji"))?;
+    resvg::render(&tree, usvg::Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap)
+}
@@ -0,0 +1,209 @@
+use axum::extract::rejection::JsonRejection;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
+
+/// Every way a render request can fail. Rendered as
+/// `{"code": "...", "message": "...", "stage": "..."}` with a 4xx status for
+/// caller mistakes and a 5xx status for faults on our side.
+#[derive(Debug, thiserror::Error)]
+pub enum RenderError {
+    /// Body was not valid JSON or did not match the request struct.
+    #[error("{message}")]
+    Payload { status: StatusCode, message: String },
+    /// Declarative model validation failed; every offending field is reported.
+    #[error("{} invalid field(s)", .0.len())]
+    InvalidFields(Vec<FieldError>),
+    /// A template or frame set the caller asked for does not exist.
+    #[error("{0} not found")]
+    NotFound(String),
+    /// A bundled asset (role template PNG, ...) could not be read.
+    #[error("failed to load {what}: {source}")]
+    Asset {
+        what: &'static str,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to render template: {0}")]
+    Template(#[from] askama::Error),
+    #[error("failed to parse SVG: {0}")]
+    Parse(#[from] usvg::Error),
+    #[error("failed to decode {0}")]
+    Decode(String),
+    #[error("failed to allocate {0}x{1} pixmap")]
+    Allocation(u32, u32),
+    #[error("failed to encode image: {0}")]
+    Encode(#[from] image::ImageError),
+    #[error("failed to build archive: {0}")]
+    Archive(#[from] zip::result::ZipError),
+    /// The render pool's queue is full; the caller should back off and retry.
+    #[error("render queue is full, retry in {retry_after_secs}s")]
+    Busy { retry_after_secs: u64 },
+    /// A render task on the blocking pool panicked or was cancelled.
+    #[error("render worker failed: {0}")]
+    Worker(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// JSON shape of an error, also embedded per-job in batch manifests.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub stage: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+impl From<RenderError> for ErrorBody {
+    fn from(error: RenderError) -> Self {
+        let code = error.code();
+        let stage = error.stage();
+        let message = error.to_string();
+        let fields = match error {
+            RenderError::InvalidFields(fields) => fields,
+            _ => Vec::new(),
+        };
+        Self { code, message, stage, fields }
+    }
+}
+
+impl RenderError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Payload { status, .. } => *status,
+            Self::InvalidFields(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Busy { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Asset { .. }
+            | Self::Template(_)
+            | Self::Parse(_)
+            | Self::Decode(_)
+            | Self::Allocation(..)
+            | Self::Encode(_)
+            | Self::Archive(_)
+            | Self::Worker(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable machine-readable identifier for the Node.js side.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Payload { .. } => "invalid_payload",
+            Self::InvalidFields(_) => "validation_failed",
+            Self::NotFound(_) => "not_found",
+            Self::Asset { .. } => "asset_unavailable",
+            Self::Template(_) => "template_error",
+            Self::Parse(_) => "svg_parse_error",
+            Self::Decode(_) => "decode_error",
+            Self::Allocation(..) => "allocation_failed",
+            Self::Encode(_) => "encode_error",
+            Self::Archive(_) => "archive_error",
+            Self::Busy { .. } => "render_queue_full",
+            Self::Worker(_) => "worker_failed",
+        }
+    }
+
+    /// Pipeline stage the failure happened in.
+    pub fn stage(&self) -> &'static str {
+        match self {
+            Self::Payload { .. } | Self::InvalidFields(_) => "validation",
+            Self::NotFound(_) | Self::Asset { .. } => "fetch",
+            Self::Template(_) => "template",
+            Self::Parse(_) => "parse",
+            Self::Decode(_) => "decode",
+            Self::Allocation(..) | Self::Worker(_) => "render",
+            Self::Busy { .. } => "queue",
+            Self::Encode(_) | Self::Archive(_) => "encode",
+        }
+    }
+}
+
+impl From<JsonRejection> for RenderError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::Payload {
+            status: rejection.status(),
+            message: rejection.body_text(),
+        }
+    }
+}
+
+impl From<ValidationErrors> for RenderError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut fields = Vec::new();
+        flatten_errors("", &errors, &mut fields);
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+        Self::InvalidFields(fields)
+    }
+}
+
+/// Walks nested/list validation errors into flat `users[3].emojis[0].hex` paths.
+/// `output` is `#[serde(flatten)]`ed into every request, so its fields are
+/// reported where the caller wrote them (`quality`, not `output.quality`).
+fn flatten_errors(prefix: &str, errors: &ValidationErrors, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let path = match (prefix, *field, kind) {
+            (_, "output", ValidationErrorsKind::Struct(_)) => prefix.to_string(),
+            ("", _, _) => field.to_string(),
+            _ => format!("{}.{}", prefix, field),
+        };
+        match kind {
+            ValidationErrorsKind::Field(errs) => {
+                out.extend(errs.iter().map(|e| FieldError { field: path.clone(), message: describe(e) }));
+            }
+            ValidationErrorsKind::Struct(inner) => flatten_errors(&path, inner, out),
+            ValidationErrorsKind::List(items) => {
+                for (index, inner) in items {
+                    flatten_errors(&format!("{}[{}]", path, index), inner, out);
+                }
+            }
+        }
+    }
+}
+
+/// Human-readable text for a validator error; built-in validators carry no message.
+fn describe(error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return message.to_string();
+    }
+    let param = |key: &str| error.params.get(key).map(|v| v.to_string());
+    let bounds = match (param("min"), param("max")) {
+        (Some(min), Some(max)) => format!("between {} and {}", min, max),
+        (Some(min), None) => format!("at least {}", min),
+        (None, Some(max)) => format!("at most {}", max),
+        (None, None) => return format!("failed '{}' check", error.code),
+    };
+    match error.code.as_ref() {
+        "length" => format!("length must be {}", bounds),
+        _ => format!("must be {}", bounds),
+    }
+}
+
+impl IntoResponse for RenderError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("Render failed [{}/{}]: {}", self.stage(), self.code(), self);
+        } else {
+            tracing::debug!("Rejected render request [{}]: {}", self.code(), self);
+        }
+        metrics::counter!("renderer_errors_total", "code" => self.code()).increment(1);
+
+        let retry_after = match &self {
+            Self::Busy { retry_after_secs } => Some(*retry_after_secs),
+            _ => None,
+        };
+        let mut response = (status, Json(ErrorBody::from(self))).into_response();
+        if let Some(secs) = retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
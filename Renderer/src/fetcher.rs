@@ -0,0 +1,178 @@
+use bytes::{Bytes, BytesMut};
+use reqwest::{redirect, Client, Url};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Hosts we fetch avatars, role icons and clan icons from unless overridden
+/// with `FETCH_ALLOWED_HOSTS`.
+const DEFAULT_ALLOWED_HOSTS: &[&str] = &["cdn.discordapp.com", "media.discordapp.net"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("host not allowed: {0}")]
+    HostNotAllowed(String),
+    #[error("upstream returned {0}")]
+    Status(reqwest::StatusCode),
+    #[error("request timed out")]
+    Timeout,
+    #[error("response exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("response is not a supported image")]
+    NotImage,
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+}
+
+impl FetchError {
+    /// Short label used as the `reason` on the fetch failure counter.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::InvalidUrl(_) => "invalid_url",
+            Self::HostNotAllowed(_) => "host_not_allowed",
+            Self::Status(_) => "status",
+            Self::Timeout => "timeout",
+            Self::TooLarge(_) => "too_large",
+            Self::NotImage => "not_image",
+            Self::Transport(_) => "transport",
+        }
+    }
+}
+
+pub struct FetcherConfig {
+    pub timeout: Duration,
+    pub max_bytes: usize,
+    pub max_redirects: usize,
+    pub allowed_hosts: Vec<String>,
+}
+
+impl FetcherConfig {
+    /// Reads `FETCH_TIMEOUT_MS`, `FETCH_MAX_BYTES`, `FETCH_MAX_REDIRECTS` and
+    /// `FETCH_ALLOWED_HOSTS` (comma separated), falling back to sane defaults.
+    pub fn from_env() -> Self {
+        fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+            std::env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        }
+
+        let allowed_hosts = match std::env::var("FETCH_ALLOWED_HOSTS") {
+            Ok(list) => list
+                .split(',')
+                .map(|h| h.trim().to_ascii_lowercase())
+                .filter(|h| !h.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_ALLOWED_HOSTS.iter().map(|h| h.to_string()).collect(),
+        };
+
+        Self {
+            timeout: Duration::from_millis(env_or("FETCH_TIMEOUT_MS", 5_000)),
+            max_bytes: env_or("FETCH_MAX_BYTES", 8 * 1024 * 1024),
+            max_redirects: env_or("FETCH_MAX_REDIRECTS", 3),
+            allowed_hosts,
+        }
+    }
+}
+
+/// Single pooled HTTP client for every remote image the renderer pulls in.
+/// Enforces a host allowlist (including on redirects), a per-request timeout,
+/// a hard body size cap and checks that the payload is actually an image.
+pub struct AssetFetcher {
+    client: Client,
+    max_bytes: usize,
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl AssetFetcher {
+    pub fn new(config: FetcherConfig) -> anyhow::Result<Self> {
+        let allowed_hosts = Arc::new(config.allowed_hosts);
+
+        let redirect_hosts = allowed_hosts.clone();
+        let max_redirects = config.max_redirects;
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= max_redirects {
+                attempt.error("too many redirects")
+            } else if !host_allowed(&redirect_hosts, attempt.url()) {
+                attempt.stop()
+            } else {
+                attempt.follow()
+            }
+        });
+
+        let client = Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.timeout)
+            .redirect(policy)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .pool_max_idle_per_host(32)
+            .user_agent("ryan-renderer/1.0")
+            .build()?;
+
+        Ok(Self {
+            client,
+            max_bytes: config.max_bytes,
+            allowed_hosts,
+        })
+    }
+
+    /// Fetches `url` and returns the raw body. Failures are counted on
+    /// `renderer_fetch_failures_total{reason}` before being returned.
+    pub async fn fetch(&self, url: &str) -> Result<Bytes, FetchError> {
+        let start = Instant::now();
+        let result = self.fetch_inner(url).await;
+        metrics::histogram!("renderer_fetch_duration_seconds").record(start.elapsed().as_secs_f64());
+
+        if let Err(e) = &result {
+            metrics::counter!("renderer_fetch_failures_total", "reason" => e.reason()).increment(1);
+            tracing::warn!("Fetch failed for {}: {}", url, e);
+        }
+        result
+    }
+
+    async fn fetch_inner(&self, url: &str) -> Result<Bytes, FetchError> {
+        let parsed = Url::parse(url).map_err(|_| FetchError::InvalidUrl(url.to_string()))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(FetchError::InvalidUrl(url.to_string()));
+        }
+        if !host_allowed(&self.allowed_hosts, &parsed) {
+            return Err(FetchError::HostNotAllowed(parsed.host_str().unwrap_or_default().to_string()));
+        }
+
+        let mut res = self.client.get(parsed).send().await.map_err(map_reqwest)?;
+        if !res.status().is_success() {
+            return Err(FetchError::Status(res.status()));
+        }
+        if res.content_length().is_some_and(|len| len as usize > self.max_bytes) {
+            return Err(FetchError::TooLarge(self.max_bytes));
+        }
+
+        // Stream the body so a lying or missing Content-Length cannot blow the cap.
+        let mut body = BytesMut::new();
+        while let Some(chunk) = res.chunk().await.map_err(map_reqwest)? {
+            if body.len() + chunk.len() > self.max_bytes {
+                return Err(FetchError::TooLarge(self.max_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        // Sniff the magic bytes — Discord's CDN is not always honest about Content-Type.
+        if image::guess_format(&body).is_err() {
+            return Err(FetchError::NotImage);
+        }
+
+        Ok(body.freeze())
+    }
+}
+
+fn host_allowed(allowed: &[String], url: &Url) -> bool {
+    url.host_str()
+        .map(|host| allowed.iter().any(|a| a.eq_ignore_ascii_case(host)))
+        .unwrap_or(false)
+}
+
+fn map_reqwest(e: reqwest::Error) -> FetchError {
+    if e.is_timeout() {
+        FetchError::Timeout
+    } else {
+        FetchError::Transport(e)
+    }
+}
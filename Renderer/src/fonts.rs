@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use usvg::fontdb::{Database, Source, Style};
+
+use crate::text::{script_runs, FontStack, TextLayout};
+
+/// Built-in stacks, used for any role the config file does not set. A role can
+/// be overridden for every template (`"username"`) or for one (`"leaderboard.username"`).
+const DEFAULT_STACKS: &[(&str, &str)] = &[
+    // usvg's fallback for text with no usable `font-family`.
+    ("default", "Poppins, DejaVu Sans, Noto Color Emoji, Noto Sans Math, Symbola, sans-serif"),
+    ("leaderboard.default", "Poppins, DejaVu Sans, Noto Color Emoji, Noto Sans Math, Noto Sans Arabic, Symbola, sans-serif"),
+    ("rank_card_bg.default", "Poppins, DejaVu Sans, sans-serif"),
+    ("username", "Poppins, sans-serif"),
+    // Tried for a username script run the `username` stack cannot draw.
+    ("username_system", "'Noto Sans', 'DejaVu Sans', sans-serif"),
+    // Rank, separators and XP on the leaderboard; LVL/RANK and XP on the rank card.
+    ("label", "Poppins, DejaVu Sans, Noto Color Emoji, sans-serif"),
+    ("role_name", "Poppins, DejaVu Sans, sans-serif"),
+];
+
+/// One representative character per script reported in `GET /fonts` coverage.
+/// Names follow Unicode script names, as used for per-script stacks.
+const COVERAGE_SAMPLES: &[(&str, char)] = &[
+    ("Latin", 'A'),
+    ("Greek", 'Ω'),
+    ("Cyrillic", 'Ж'),
+    ("Armenian", 'Ա'),
+    ("Hebrew", 'א'),
+    ("Arabic", 'ع'),
+    ("Devanagari", 'क'),
+    ("Bengali", 'অ'),
+    ("Tamil", 'த'),
+    ("Thai", 'ก'),
+    ("Georgian", 'ა'),
+    ("Ethiopic", 'አ'),
+    ("Hangul", '한'),
+    ("Hiragana", 'あ'),
+    ("Katakana", 'ア'),
+    ("Han", '中'),
+    ("Math", '𝐀'),
+    ("Symbols", '★'),
+    ("Emoji", '😀'),
+];
+
+/// `fonts.json`: extra named stacks, keyed by role or `template.role`, and
+/// stacks per Unicode script.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FontConfig {
+    stacks: HashMap<String, String>,
+    scripts: HashMap<String, String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FaceInfo {
+    pub weight: u16,
+    pub style: &'static str,
+    /// File the face was loaded from, relative to the font directory when inside it.
+    pub source: String,
+    pub glyphs: u16,
+    /// Scripts from `COVERAGE_SAMPLES` the face has a glyph for.
+    pub coverage: Vec<&'static str>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FamilyInfo {
+    pub family: String,
+    pub faces: Vec<FaceInfo>,
+}
+
+/// A stretch of text and the `font-family` chosen to draw it.
+pub struct FontRun<'a> {
+    pub text: &'a str,
+    pub font: String,
+}
+
+/// Body of `GET /fonts`.
+#[derive(Serialize, Clone)]
+pub struct FontsInfo {
+    pub families: Vec<FamilyInfo>,
+    pub stacks: BTreeMap<String, String>,
+    pub scripts: BTreeMap<String, String>,
+}
+
+/// Every font the renderer can use plus the named stacks templates draw with.
+///
+/// System fonts and the font directory are loaded once at startup into a single
+/// fontdb, which both `TextLayout` measurement and usvg rasterization share, so a
+/// layout decision is always made against the faces that will draw it.
+pub struct FontRegistry {
+    db: Arc<Database>,
+    stacks: HashMap<String, String>,
+    scripts: HashMap<String, String>,
+    /// Best covering installed family per (script, weight), found on first use.
+    script_families: RwLock<HashMap<(&'static str, u16), Option<String>>>,
+    info: FontsInfo,
+}
+
+impl FontRegistry {
+    pub fn load(dir: &Path, config: Option<&Path>) -> anyhow::Result<Self> {
+        let mut db = Database::new();
+        db.load_system_fonts();
+        let system_faces = db.len();
+        db.load_fonts_dir(dir);
+        tracing::info!(
+            "Loaded {} system font faces and {} from {}",
+            system_faces,
+            db.len() - system_faces,
+            dir.display()
+        );
+
+        let config: FontConfig = match config {
+            Some(path) => serde_json::from_slice(&std::fs::read(path)?)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?,
+            None => FontConfig::default(),
+        };
+        let mut stacks: HashMap<String, String> =
+            DEFAULT_STACKS.iter().map(|(name, css)| (name.to_string(), css.to_string())).collect();
+        stacks.extend(config.stacks);
+
+        // Every template draws with the first family of the default stack; without
+        // it text would silently fall back to whatever face fontdb picks.
+        let primary = stacks["default"].split(',').next().unwrap_or_default().trim().trim_matches(['\'', '"']);
+        let generic = matches!(primary, "serif" | "sans-serif" | "cursive" | "fantasy" | "monospace");
+        if !generic && !db.faces().any(|face| face.families.iter().any(|(family, _)| family.eq_ignore_ascii_case(primary))) {
+            anyhow::bail!(
+                "default font family '{}' not found in {} or the system fonts; set FONT_DIR to a directory that has it",
+                primary,
+                dir.display()
+            );
+        }
+
+        let info = FontsInfo {
+            families: describe(&db, dir),
+            stacks: stacks.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            scripts: config.scripts.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        };
+        metrics::gauge!("renderer_font_faces_loaded").set(db.len() as f64);
+
+        Ok(Self {
+            db: Arc::new(db),
+            stacks,
+            scripts: config.scripts,
+            script_families: RwLock::new(HashMap::new()),
+            info,
+        })
+    }
+
+    /// `FONT_DIR` (default `./assets/fonts`, then `../assets/fonts`) and
+    /// `FONT_CONFIG` (default `fonts.json` in the font directory, if present).
+    /// Fails if the font directory does not exist.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let dir = match std::env::var("FONT_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ if Path::new("./assets/fonts").exists() => PathBuf::from("./assets/fonts"),
+            _ => PathBuf::from("../assets/fonts"),
+        };
+        if !dir.is_dir() {
+            anyhow::bail!("font directory {} does not exist; set FONT_DIR", dir.display());
+        }
+        let config = match std::env::var("FONT_CONFIG") {
+            Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+            _ => Some(dir.join("fonts.json")).filter(|path| path.exists()),
+        };
+        Self::load(&dir, config.as_deref())
+    }
+
+    pub fn db(&self) -> Arc<Database> {
+        self.db.clone()
+    }
+
+    /// CSS `font-family` list for `role` in `template` (its `SvgTemplate::FILE`
+    /// stem), falling back to the role's template-independent stack.
+    pub fn stack(&self, template: &str, role: &str) -> &str {
+        self.stacks
+            .get(&format!("{}.{}", template, role))
+            .or_else(|| self.stacks.get(role))
+            .map(String::as_str)
+            .unwrap_or("sans-serif")
+    }
+
+    /// Splits `text` into script runs and picks a `font-family` for each, so one
+    /// Cyrillic letter does not move a whole Latin name out of its font. A run
+    /// keeps `base` wherever `base` covers it; otherwise it gets the script's
+    /// configured stack, then the `fallback` role's stack, then the installed
+    /// family covering most of it, each tried only if it covers the run, and the
+    /// last put in front of `base` so usvg can still fall back glyph by glyph.
+    pub fn font_runs<'a>(
+        &self,
+        layout: &TextLayout,
+        text: &'a str,
+        template: &str,
+        base: &str,
+        fallback: &str,
+        bold: bool,
+    ) -> Vec<FontRun<'a>> {
+        let parse = |css: &str| {
+            let stack = FontStack::parse(css);
+            if bold { stack.bold() } else { stack }
+        };
+        let base_stack = parse(base);
+
+        script_runs(text)
+            .into_iter()
+            .map(|(script, run)| {
+                if layout.covers(run, &base_stack) {
+                    return FontRun { text: run, font: base.to_string() };
+                }
+                let configured = self
+                    .scripts
+                    .get(script.full_name())
+                    .map(String::as_str)
+                    .into_iter()
+                    .chain(Some(self.stack(template, fallback)))
+                    .find(|css| layout.covers(run, &parse(css)));
+                let font = match configured {
+                    Some(css) => css.to_string(),
+                    None => match self.best_family(layout, script.full_name(), run, base_stack.weight()) {
+                        Some(family) => format!("{}, {}", family, base),
+                        None => base.to_string(),
+                    },
+                };
+                FontRun { text: run, font }
+            })
+            .collect()
+    }
+
+    fn best_family(&self, layout: &TextLayout, script: &'static str, run: &str, weight: u16) -> Option<String> {
+        if let Some(family) = self.script_families.read().unwrap_or_else(|e| e.into_inner()).get(&(script, weight)) {
+            return family.clone();
+        }
+        let family = layout.best_family(run, weight);
+        tracing::debug!("Fallback family for {} text: {:?}", script, family);
+        self.script_families
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((script, weight), family.clone());
+        family
+    }
+
+    pub fn info(&self) -> &FontsInfo {
+        &self.info
+    }
+}
+
+/// Groups the faces in `db` by family with their script coverage.
+fn describe(db: &Database, dir: &Path) -> Vec<FamilyInfo> {
+    let mut families: BTreeMap<String, Vec<FaceInfo>> = BTreeMap::new();
+    for face in db.faces() {
+        let Some((family, _)) = face.families.first() else { continue };
+        let source = match &face.source {
+            Source::File(path) | Source::SharedFile(path, _) => {
+                path.strip_prefix(dir).unwrap_or(path).display().to_string()
+            }
+            Source::Binary(_) => "memory".to_string(),
+        };
+        let (glyphs, coverage) = db
+            .with_face_data(face.id, |data, index| {
+                let parsed = rustybuzz::ttf_parser::Face::parse(data, index).ok()?;
+                let coverage = COVERAGE_SAMPLES
+                    .iter()
+                    .filter(|(_, c)| parsed.glyph_index(*c).is_some())
+                    .map(|(script, _)| *script)
+                    .collect();
+                Some((parsed.number_of_glyphs(), coverage))
+            })
+            .flatten()
+            .unwrap_or_default();
+
+        families.entry(family.clone()).or_default().push(FaceInfo {
+            weight: face.weight.0,
+            style: match face.style {
+                Style::Normal => "normal",
+                Style::Italic => "italic",
+                Style::Oblique => "oblique",
+            },
+            source,
+            glyphs,
+            coverage,
+        });
+    }
+    families
+        .into_iter()
+        .map(|(family, faces)| FamilyInfo { family, faces })
+        .collect()
+}
@@ -1,120 +1,343 @@
-use axum::{Json, http::StatusCode, response::{IntoResponse, Response}, extract::State};
-use reqwest::Client;
-use base64::{engine::general_purpose, Engine as _};
-use usvg::{Options, Tree, TreeParsing, TreePostProc};
+use axum::{Json, extract::{rejection::JsonRejection, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}};
 use tiny_skia::Pixmap;
 use std::io::Cursor;
+use crate::models::{BatchJobKind, BatchRequest, ClanGifRequest, LeaderboardRequest, NameRun, NormalizeMode, RankCardRequest, RankCardTheme, RoleRewardBaseRequest, TextFit};
+use crate::template::{RankCardBackgroundTemplate, RankCardTemplate, RoleRewardBaseTemplate, TemplateGradientStop, TemplateTextLine};
+use crate::cache::{self, etag_matches, CachedRender};
+use crate::color_emoji::is_emoji_cluster;
+use crate::default_avatar::AvatarSeed;
+use crate::error::{ErrorBody, FieldError, RenderError};
+use crate::normalize::normalize;
+use crate::output::{AnimatedWebP, AnimationFormat, OutputFormat, OutputOptions};
+use crate::render::{self, Clip, Fit, ImageBox, RasterLayer, RenderJob};
+use crate::state::AppState;
+use crate::text::{bidi_line, collapse_whitespace, layout_name, FontStack, NameLayout, NamePiece};
+use validator::Validate;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tiny_skia::{PixmapPaint, Transform};
+use tokio::sync::Semaphore;
+use unicode_segmentation::UnicodeSegmentation;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Set when a remote image or emoji a render asked for failed to load and
+/// something else was drawn in its place. Such a render is still served, but
+/// neither cached nor given an ETag, so it is redone once the image loads again.
+#[derive(Default)]
+pub struct Fallbacks(AtomicBool);
+
+impl Fallbacks {
+    fn record(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
 
-fn to_png_b64(bytes: &[u8]) -> String {
-    if bytes.is_empty() {
-        return "".to_string();
+    fn any(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
     }
-    match image::load_from_memory(bytes) {
-        Ok(img) => {
-            let mut buf = Cursor::new(Vec::new());
-            if img.write_to(&mut buf, image::ImageFormat::Png).is_ok() {
-                general_purpose::STANDARD.encode(&buf.into_inner())
-            } else {
-                "".to_string()
+}
+
+/// Returns the avatar/icon at `url` decoded and sized for `target`, served from
+/// `avatar_cache` when possible, then from the body kept in `disk_cache` before
+/// going to the network. Any failure (empty url, fetch error,
+/// undecodable image) yields `None`; failures other than an empty url are
+/// recorded in `fallbacks`.
+async fn fetch_image(state: &AppState, url: &str, target: ImageBox, fallbacks: &Fallbacks) -> Option<Arc<Pixmap>> {
+    if url.is_empty() {
+        return None;
+    }
+    let image = state
+        .avatar_cache
+        .get_or_fetch(url, target, || async {
+            if let Some(disk) = &state.disk_cache {
+                if let Some(bytes) = disk.get(url).await {
+                    return Ok(bytes);
+                }
             }
-        }
-        Err(_) => "".to_string(),
+            let bytes = state.fetcher.fetch(url).await?;
+            if let Some(disk) = &state.disk_cache {
+                disk.insert(url, &bytes).await;
+            }
+            Ok(bytes)
+        })
+        .await;
+    if image.is_none() {
+        fallbacks.record();
     }
+    image
 }
 
-/// Normalizes fancy mathematical alphanumeric characters back to standard Latin characters
-fn normalize_discord_name(input: &str) -> String {
-    input.chars().map(|c| {
-        let u = c as u32;
-        match u {
-            // Mathematical Bold Capital (A-Z) -> 𝐆, 𝐅, etc.
-            0x1D400..=0x1D419 => std::char::from_u32(u - 0x1D400 + 0x0041).unwrap_or(c),
-            // Mathematical Bold Small (a-z)
-            0x1D41A..=0x1D433 => std::char::from_u32(u - 0x1D41A + 0x0061).unwrap_or(c),
-            // Mathematical Italic Capital
-            0x1D434..=0x1D44D => std::char::from_u32(u - 0x1D434 + 0x0041).unwrap_or(c),
-            // Mathematical Italic Small
-            0x1D44E..=0x1D467 => std::char::from_u32(u - 0x1D44E + 0x0061).unwrap_or(c),
-            // Mathematical Script Capital (e.g., 𝓨)
-            0x1D49C..=0x1D4B5 => std::char::from_u32(u - 0x1D49C + 0x0041).unwrap_or(c),
-            // Mathematical Script Small (e.g., 𝓮, 𝓸, 𝓵)
-            0x1D4B6..=0x1D4CF => std::char::from_u32(u - 0x1D4B6 + 0x0061).unwrap_or(c),
-            // Mathematical Fraktur Capital (e.g., 𝔐)
-            0x1D504..=0x1D51D => std::char::from_u32(u - 0x1D504 + 0x0041).unwrap_or(c),
-            // Mathematical Fraktur Small (e.g., 𝔞, 𝔰, 𝔥)
-            0x1D51E..=0x1D537 => std::char::from_u32(u - 0x1D51E + 0x0061).unwrap_or(c),
-            _ => c,
+/// The image at `url` sized for `target`, or the generated default avatar for
+/// `seed` when the URL is empty or fails to load.
+async fn avatar_or_default(
+    state: &AppState,
+    url: &str,
+    target: ImageBox,
+    seed: AvatarSeed<'_>,
+    fallbacks: &Fallbacks,
+) -> Arc<Pixmap> {
+    match fetch_image(state, url, target, fallbacks).await {
+        Some(image) => image,
+        None => state.default_avatars.get(&seed, target.width.max(target.height)).await,
+    }
+}
+
+/// The image for emoji `hex`, or the fallback glyph (recorded in `fallbacks`)
+/// when there is no asset for it yet. `None` for hexes that draw nothing.
+async fn emoji_image(state: &AppState, hex: &str, fallbacks: &Fallbacks) -> Option<Arc<Pixmap>> {
+    let (image, fell_back) = state.emojis.get_or_fallback(hex).await?;
+    if fell_back {
+        fallbacks.record();
+    }
+    Some(image)
+}
+
+/// Boxes remote images are drawn into on each template.
+const RANK_CARD_AVATAR: ImageBox = ImageBox::contain(150, 150);
+const RANK_CARD_BACKGROUND: ImageBox = ImageBox::cover(800, 250);
+const RANK_CARD_EMOJI: ImageBox = ImageBox::contain(42, 42);
+const LEADERBOARD_AVATAR: ImageBox = ImageBox::contain(57, 58);
+const LEADERBOARD_EMOJI: ImageBox = ImageBox::contain(30, 30);
+const DEFAULT_ROLE_ICON_SIZE: u32 = 600;
+const DEFAULT_CLAN_ICON_SIZE: u32 = 60;
+
+/// Resolves a display name to pieces: `runs` when given, otherwise the plain
+/// `username` as a single text piece. Unicode emoji without an asset get the
+/// fallback glyph; custom emoji are fetched at `emoji` size, and fall back to
+/// their `:name:` text if they fail to load.
+async fn resolve_name(
+    state: &AppState,
+    username: &str,
+    runs: Option<&[NameRun]>,
+    mode: NormalizeMode,
+    emoji: ImageBox,
+    fallbacks: &Fallbacks,
+) -> Vec<NamePiece> {
+    let Some(runs) = runs else {
+        return split_color_emoji(state, vec![NamePiece::Text(normalize(username, mode))]).await;
+    };
+    let pieces = runs.iter().map(|run| async move {
+        match run {
+            NameRun::Text { text } => Some(NamePiece::Text(normalize(text, mode))),
+            NameRun::Emoji { hex } => emoji_image(state, hex, fallbacks).await.map(NamePiece::Image),
+            NameRun::CustomEmoji { name, .. } => {
+                let image = fetch_image(state, &run.custom_emoji_url().unwrap_or_default(), emoji, fallbacks).await;
+                Some(image.map_or_else(|| NamePiece::Text(format!(":{}:", name)), NamePiece::Image))
+            }
         }
-    }).collect()
+    });
+    let pieces = futures::future::join_all(pieces).await.into_iter().flatten().collect();
+    split_color_emoji(state, pieces).await
 }
 
-/// Detects if a string contains non-standard scripts (including Latin Extended) 
-/// that require a unified system font to avoid the "Frankenstein" effect.
-fn requires_system_font(text: &str) -> bool {
-    text.chars().any(|c| {
-        let u = c as u32;
-        
-        // 1. Latin Extended (Catches Ş, æ, ø, ğ, ł, á, etc.)
-        (0x0080..=0x02AF).contains(&u) ||
-        
-        // 2. Greek, Cyrillic, Arabic, Indic, Thai, etc.
-        (0x0370..=0x1FFF).contains(&u) || 
-        
-        // 3. CJK Ideographs & Kana
-        (0x2E80..=0x9FFF).contains(&u) || 
-        
-        // 4. Hangul (Korean)
-        (0xAC00..=0xD7AF).contains(&u) || 
-        
-        // 5. CJK Compatibility & Arabic Presentation
-        (0xF900..=0xFDFF).contains(&u) ||
-        (0xFE70..=0xFEFF).contains(&u) ||
-        
-        // 6. Fullwidth Forms
-        (0xFF00..=0xFFEF).contains(&u) ||
-        
-        // 7. CJK Extensions
-        (0x20000..=0x2FA1F).contains(&u)
+/// Pulls emoji typed directly into text out as images from the colour emoji
+/// font, so they render in colour inline. Clusters the font cannot draw stay text.
+async fn split_color_emoji(state: &AppState, pieces: Vec<NamePiece>) -> Vec<NamePiece> {
+    let Some(font) = &state.color_emoji else {
+        return pieces;
+    };
+    let mut split = Vec::new();
+    for piece in pieces {
+        let NamePiece::Text(text) = piece else {
+            split.push(piece);
+            continue;
+        };
+        let mut pending = String::new();
+        for cluster in text.graphemes(true) {
+            let image = if is_emoji_cluster(cluster) { font.render(cluster).await } else { None };
+            match image {
+                Some(glyph) => {
+                    if !pending.is_empty() {
+                        split.push(NamePiece::Text(std::mem::take(&mut pending)));
+                    }
+                    split.push(NamePiece::Image(glyph));
+                }
+                None => pending.push_str(cluster),
+            }
+        }
+        if !pending.is_empty() {
+            split.push(NamePiece::Text(pending));
+        }
+    }
+    split
+}
+
+/// Smallest size `fit: shrink` will go to before falling back to an ellipsis.
+const MIN_ROLE_FONT_SIZE: u32 = 48;
+
+/// Largest size at or below `requested` at which `emoji_count` emoji plus a
+/// name `unit_width` wide at 1px fit `box_width`, but not below
+/// `MIN_ROLE_FONT_SIZE` unless the caller asked for less.
+fn shrink_font_size(box_width: f32, emoji_count: f32, unit_width: f32, requested: u32) -> u32 {
+    // Advances scale linearly with size, so the total width is
+    // emoji_count * (size + 15) + size * width_at_1px — solve for size.
+    let per_px = emoji_count + unit_width;
+    let fitted = if per_px > 0.0 { ((box_width - 15.0 * emoji_count) / per_px).floor() } else { f32::MAX };
+    let min = MIN_ROLE_FONT_SIZE.min(requested);
+    fitted.min(requested as f32).max(min as f32) as u32
+}
+
+/// Space kept clear between the role name and the right edge of the role reward canvas.
+const ROLE_NAME_RIGHT_MARGIN: f32 = 80.0;
+
+fn resolve_format(output: &OutputOptions, headers: &HeaderMap) -> Result<OutputFormat, RenderError> {
+    output.resolve(headers).map_err(|bad| {
+        RenderError::InvalidFields(vec![FieldError {
+            field: "format".to_string(),
+            message: format!("unsupported output format: {}", bad),
+        }])
     })
 }
 
-use crate::models::{RankCardRequest, RoleRewardBaseRequest};
-use crate::template::{RankCardTemplate, RoleRewardBaseTemplate};
-use crate::state::AppState;
-use askama::Template;
-use std::sync::Arc;
-use std::time::Instant;
+fn cached_response(render: &CachedRender) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (axum::http::header::CONTENT_TYPE, render.content_type),
+            (axum::http::header::ETAG, render.etag.as_str()),
+        ],
+        render.bytes.clone(),
+    ).into_response()
+}
+
+/// Answers from the output cache when possible: 304 if the caller already holds
+/// this exact render, the cached bytes on a hit, `None` if it must be rendered.
+async fn cached_output(state: &AppState, headers: &HeaderMap, route: &'static str, key: &str) -> Option<Response> {
+    if etag_matches(headers, key) {
+        metrics::counter!("renderer_output_not_modified_total", "route" => route).increment(1);
+        return Some((StatusCode::NOT_MODIFIED, [(axum::http::header::ETAG, key)]).into_response());
+    }
+    state.output_cache.get(route, key).await.map(|hit| cached_response(&hit))
+}
+
+/// Caches a fresh render and answers with it and its ETag. A render in which an
+/// image fell back is answered as-is, uncached and without an ETag.
+async fn store_output(
+    state: &AppState,
+    route: &'static str,
+    key: String,
+    content_type: &'static str,
+    bytes: Vec<u8>,
+    fallbacks: &Fallbacks,
+) -> Response {
+    if fallbacks.any() {
+        metrics::counter!("renderer_output_uncached_total", "route" => route).increment(1);
+        return (
+            StatusCode::OK,
+            [
+                (axum::http::header::CONTENT_TYPE, content_type),
+                (axum::http::header::CACHE_CONTROL, "no-store"),
+            ],
+            bytes,
+        )
+            .into_response();
+    }
+    let render = state.output_cache.insert(key, content_type, bytes).await;
+    cached_response(&render)
+}
 
 pub async fn render_rank_card(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<RankCardRequest>,
-) -> Response {
+    headers: HeaderMap,
+    payload: Result<Json<RankCardRequest>, JsonRejection>,
+) -> Result<Response, RenderError> {
     let start = Instant::now();
+    let Json(payload) = payload?;
+    payload.validate()?;
+    let format = resolve_format(&payload.output, &headers)?;
 
-    // 1. Fetch Discord Avatar & Convert to Base64
-    let avatar_b64 = if let Some(cached) = state.avatar_cache.get(&payload.avatar_url).await {
-        cached
-    } else {
-        let client = Client::new();
-        let avatar_bytes = match client.get(&payload.avatar_url).send().await {
-            Ok(res) if res.status().is_success() => res.bytes().await.unwrap_or_default().to_vec(),
-            _ => {
-                let mut buf = tokio::fs::read("./assets/default_avatar.png").await;
-                if buf.is_err() {
-                    buf = tokio::fs::read("../assets/default_avatar.png").await;
-                }
-                buf.unwrap_or_default()
-            }
-        };
-        
-        let b64 = to_png_b64(&avatar_bytes);
-        
-        if !payload.avatar_url.is_empty() && !b64.is_empty() {
-            state.avatar_cache.insert(payload.avatar_url.clone(), b64.clone()).await;
-        }
-        
-        b64
+    let key = state.output_cache.key("rank_card", &payload, format.content_type());
+    if let Some(response) = cached_output(&state, &headers, "rank_card", &key).await {
+        return Ok(response);
+    }
+
+    let fallbacks = Fallbacks::default();
+    let job = prepare_rank_card(&state, payload, format, &fallbacks).await?;
+    let image_bytes = state.render_pool.rasterize(job, state.fonts.db()).await?;
+
+    // Record Metrics
+    let duration = start.elapsed().as_secs_f64();
+    tracing::debug!("Recording render duration: {}s", duration);
+    metrics::histogram!("renderer_render_duration_seconds").record(duration);
+
+    // Return the raw image bytes to Node.js
+    Ok(store_output(&state, "rank_card", key, format.content_type(), image_bytes, &fallbacks).await)
+}
+
+/// The gradient background and background image for `theme` under the current
+/// template version, baked on the render pool the first time that theme is seen.
+pub async fn rank_card_background(
+    state: &AppState,
+    theme: &RankCardTheme,
+    fallbacks: &Fallbacks,
+) -> Result<Arc<Pixmap>, RenderError> {
+    let key = format!("{}:{}", state.templates.version(), cache::hash_json(theme));
+    if let Some(bg) = state.rank_card_bg.get(&key).await {
+        return Ok(bg);
+    }
+
+    let image_url = theme.background_image_url.as_deref().unwrap_or("");
+    let background = fetch_image(state, image_url, RANK_CARD_BACKGROUND, fallbacks).await;
+    // A missing image still renders (gradient only) but is not cached, so the
+    // next request for this theme tries the fetch again.
+    let complete = image_url.is_empty() || background.is_some();
+    let layers: Vec<RasterLayer> = background
+        .map(|image| {
+            RasterLayer::new(image, 0.0, 0.0, 800.0, 250.0)
+                .fit(Fit::Cover)
+                .clip(Clip::RoundedRect(theme.corner_radius))
+        })
+        .into_iter()
+        .collect();
+
+    let template = RankCardBackgroundTemplate {
+        stops: theme
+            .background_stops
+            .iter()
+            .map(|stop| TemplateGradientStop { offset: stop.offset, color: stop.color.clone() })
+            .collect(),
+        corner_radius: theme.corner_radius,
     };
+    let svg = state.templates.render(&template)?;
+    let fontdb = state.fonts.db();
+    let font_family = state.fonts.stack("rank_card_bg", "default").to_string();
+    let bg = state
+        .render_pool
+        .run(move || {
+            let canvas = Pixmap::new(800, 250).ok_or(RenderError::Allocation(800, 250))?;
+            let mut bg = render::draw(&svg, &font_family, canvas, &fontdb)?;
+            render::composite(&mut bg, &layers);
+            Ok::<_, RenderError>(bg)
+        })
+        .await??;
+
+    let bg = Arc::new(bg);
+    if complete {
+        state.rank_card_bg.insert(key, bg.clone()).await;
+    }
+    Ok(bg)
+}
+
+async fn prepare_rank_card(
+    state: &AppState,
+    payload: RankCardRequest,
+    format: OutputFormat,
+    fallbacks: &Fallbacks,
+) -> Result<RenderJob, RenderError> {
+    // 1. Fetch Discord Avatar
+    let seed = AvatarSeed {
+        user_id: payload.user_id.as_deref(),
+        discriminator: payload.discriminator.as_deref(),
+        name: &payload.username,
+    };
+    let avatar = avatar_or_default(state, &payload.avatar_url, RANK_CARD_AVATAR, seed, fallbacks).await;
 
     // 2. Math for Progress Bar (Max width is 500px)
     let progress_percent = if payload.next_xp > 0 {
@@ -124,107 +347,119 @@ pub async fn render_rank_card(
     };
     let progress_width = (progress_percent * 500.0).clamp(0.0, 500.0);
 
-    // 3. Populate Askama SVG Template (dynamic layer only — no bg rect, no trough)
-    let normalized_username = normalize_discord_name(&payload.username);
-    let use_system_font = requires_system_font(&normalized_username);
+    // 3. Fit the username between the avatar and the right-aligned LVL/RANK line
+    let name = resolve_name(
+        state,
+        &payload.username,
+        payload.username_runs.as_deref(),
+        payload.normalize,
+        RANK_CARD_EMOJI,
+        fallbacks,
+    )
+    .await;
+    let label_css = state.fonts.stack("rank_card", "label");
+    let stats_font = FontStack::parse(label_css);
+    let stats_width = state.text.measure("LVL ", &stats_font, 28.0)
+        + state.text.measure(&payload.level.to_string(), &stats_font, 42.0)
+        + 15.0
+        + state.text.measure("RANK ", &stats_font, 28.0)
+        + state.text.measure(&format!("#{}", payload.rank), &stats_font, 42.0);
+    let max_username_width = 750.0 - 250.0 - stats_width - 20.0;
+    let name = layout_name(
+        &state.fonts,
+        &state.text,
+        name,
+        "rank_card",
+        42.0,
+        250.0,
+        max_username_width.max(0.0),
+    );
+
+    // 4. Raster layers: circle-clipped avatar and inline emoji
+    let layers = std::iter::once(RasterLayer::new(avatar, 50.0, 50.0, 150.0, 150.0).clip(Clip::Circle))
+        .chain(NameLayout::emoji_layers(name.images, 64.0, RANK_CARD_EMOJI.width as f32))
+        .collect();
+
+    // 5. Populate Askama SVG Template (dynamic layer only — no bg rect)
 
     let template = RankCardTemplate {
-        username: normalized_username,
-        avatar_b64,
+        name_runs: name.runs,
         current_xp: payload.current_xp,
         next_xp: payload.next_xp,
         rank: payload.rank,
         level: payload.level,
         clan_color: payload.clan_color,
         progress_width,
-        use_system_font,
-    };
-    let svg_string = match template.render() {
-        Ok(s) => s,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render template").into_response(),
-    };
-
-    // 4. Setup resvg & Font options
-    let mut opt = Options::default();
-    opt.font_family = "Poppins, DejaVu Sans, Noto Color Emoji, Noto Sans Math, Symbola, sans-serif".to_string();
-
-    // 5. Render SVG to PNG Bytes
-    let mut rtree = match Tree::from_str(&svg_string, &opt) {
-        Ok(tree) => tree,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse SVG").into_response(),
-    };
-    
-    // Convert text to paths using the loaded font database
-    rtree.postprocess(usvg::PostProcessingSteps::default(), &state.fontdb);
-
-    // 6. Clone the pre-baked background pixmap — O(n) memcpy of pixel bytes.
-    //    This pixmap already has the gradient background + progress trough painted.
-    //    We composite the dynamic SVG layer directly on top.
-    let mut pixmap = state.rank_card_bg.as_ref().clone();
-    
-    resvg::render(&rtree, usvg::Transform::default(), &mut pixmap.as_mut());
-
-    let png_bytes = match pixmap.encode_png() {
-        Ok(bytes) => bytes,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode PNG").into_response(),
+        trough_color: payload.theme.trough_color.clone(),
+        username_font: state.fonts.stack("rank_card", "username").to_string(),
+        label_font: label_css.to_string(),
+        text_color: payload.theme.text_color.clone(),
+        label_color: payload.theme.label_color.clone(),
     };
 
-    // Record Metrics
-    let duration = start.elapsed().as_secs_f64();
-    tracing::debug!("Recording render duration: {}s", duration);
-    metrics::histogram!("renderer_render_duration_seconds").record(duration);
-
-    // Return the raw PNG bytes to Node.js
-    (
-        StatusCode::OK,
-        [(axum::http::header::CONTENT_TYPE, "image/png")],
-        png_bytes,
-    ).into_response()
+    Ok(RenderJob {
+        svg: state.templates.render(&template)?,
+        font_family: state.fonts.stack("rank_card", "default").to_string(),
+        // Clone the pre-baked background pixmap — O(n) memcpy of pixel bytes.
+        // This pixmap already has the gradient background + progress trough painted;
+        // the dynamic SVG layer is composited directly on top.
+        canvas: rank_card_background(state, &payload.theme, fallbacks).await?.as_ref().clone(),
+        layers,
+        format,
+        output: payload.output.clone(),
+    })
 }
 
 pub async fn render_leaderboard(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<crate::models::LeaderboardRequest>,
-) -> Response {
+    headers: HeaderMap,
+    payload: Result<Json<LeaderboardRequest>, JsonRejection>,
+) -> Result<Response, RenderError> {
     let start = Instant::now();
-    let client = Client::new();
+    let Json(payload) = payload?;
+    payload.validate()?;
+    let format = resolve_format(&payload.output, &headers)?;
 
+    let key = state.output_cache.key("leaderboard", &payload, format.content_type());
+    if let Some(response) = cached_output(&state, &headers, "leaderboard", &key).await {
+        return Ok(response);
+    }
+
+    let fallbacks = Fallbacks::default();
+    let job = prepare_leaderboard(&state, payload, format, &fallbacks).await?;
+    let image_bytes = state.render_pool.rasterize(job, state.fonts.db()).await?;
+
+    let duration = start.elapsed().as_secs_f64();
+    tracing::debug!("Recording leaderboard render duration: {}s", duration);
+    metrics::histogram!("renderer_leaderboard_render_duration_seconds").record(duration);
+
+    Ok(store_output(&state, "leaderboard", key, format.content_type(), image_bytes, &fallbacks).await)
+}
+
+async fn prepare_leaderboard(
+    state: &AppState,
+    payload: LeaderboardRequest,
+    format: OutputFormat,
+    fallbacks: &Fallbacks,
+) -> Result<RenderJob, RenderError> {
     let mut template_users = Vec::new();
+    let mut layers = Vec::new();
     let mut y_pos = 10;
-    
-    // 1. Fetch ALL avatars concurrently
-    let mut avatar_futures = Vec::new();
-    for user in &payload.users {
-        let client_clone = client.clone();
-        let url = user.avatar_url.clone();
-        let cache = state.avatar_cache.clone();
-        avatar_futures.push(async move {
-            if !url.is_empty() {
-                if let Some(cached) = cache.get(&url).await {
-                    return cached;
-                }
-                match client_clone.get(&url).send().await {
-                    Ok(res) if res.status().is_success() => {
-                        let bytes = res.bytes().await.unwrap_or_default().to_vec();
-                        if bytes.is_empty() {
-                            "".to_string()
-                        } else {
-                            let b64 = to_png_b64(&bytes);
-                            if !b64.is_empty() {
-                                cache.insert(url.clone(), b64.clone()).await;
-                            }
-                            b64
-                        }
-                    },
-                    _ => "".to_string(),
-                }
-            } else {
-                "".to_string()
-            }
-        });
-    }
 
-    let avatars_b64 = futures::future::join_all(avatar_futures).await;
+    // 1. Fetch ALL avatars concurrently
+    let avatar_futures = payload.users.iter().map(|user| {
+        let seed = AvatarSeed {
+            user_id: Some(&user.user_id),
+            discriminator: user.discriminator.as_deref(),
+            name: &user.username,
+        };
+        avatar_or_default(state, &user.avatar_url, LEADERBOARD_AVATAR, seed, fallbacks)
+    });
+    let avatars = futures::future::join_all(avatar_futures).await;
+    let names = futures::future::join_all(payload.users.iter().map(|user| {
+        resolve_name(state, &user.username, user.username_runs.as_deref(), payload.normalize, LEADERBOARD_EMOJI, fallbacks)
+    }))
+    .await;
 
     // Map colors
     let get_bg_color = |rank: i32, is_highlighted: bool| -> String {
@@ -251,78 +486,20 @@ pub async fn render_leaderboard(
         }
     };
 
-    // 1. Load Poppins
-    let poppins_data = include_bytes!("../../assets/fonts/Poppins-Bold.ttf");
-    let poppins_face = ttf_parser::Face::parse(poppins_data, 0).unwrap();
-    let poppins_scale = 30.0 / poppins_face.units_per_em() as f64;
-
-    // 2. Load Math Font
-    let math_data = include_bytes!("../../assets/fonts/NotoSansMath-Regular.ttf");
-    let math_face = ttf_parser::Face::parse(math_data, 0).unwrap();
-    let math_scale = 30.0 / math_face.units_per_em() as f64;
-
-    // 3. Load Symbola
-    let symbola_data = include_bytes!("../../assets/fonts/Symbola.ttf");
-    let symbola_face = ttf_parser::Face::parse(symbola_data, 0).unwrap();
-    let symbola_scale = 30.0 / symbola_face.units_per_em() as f64;
-
-    // 4. Bulletproof measuring closure
-    let measure_text = |text: &str| -> f64 {
-        text.chars().map(|c| {
-            let u = c as u32;
-
-            // 1. HARD OVERRIDES (Execute BEFORE font parsing to prevent bad metrics)
-            // Em Space, Em Quad, Ideographic Space
-            if u == 0x2001 || u == 0x2003 || u == 0x3000 { return 30.0; } 
-            // En Space, En Quad
-            if u == 0x2000 || u == 0x2002 { return 15.0; } 
-            // Standard Space & NBSP
-            if u == 0x0020 || u == 0x00A0 { return 8.0; } 
-            
-            // CJK Ideographs (Force full-width 1em since they use fallback fonts)
-            if (0x4E00..=0x9FFF).contains(&u) || (0x3400..=0x4DBF).contains(&u) || (0xFF00..=0xFFEF).contains(&u) {
-                return 30.0; 
-            }
-
-            // 2. Try Poppins First
-            if let Some(glyph_id) = poppins_face.glyph_index(c) {
-                if glyph_id.0 != 0 { // Explicitly ignore the .notdef missing box
-                    if let Some(advance) = poppins_face.glyph_hor_advance(glyph_id) {
-                        return advance as f64 * poppins_scale;
-                    }
-                }
-            }
-            // 3. Try Math Font Fallback
-            if let Some(glyph_id) = math_face.glyph_index(c) {
-                if glyph_id.0 != 0 {
-                    if let Some(advance) = math_face.glyph_hor_advance(glyph_id) {
-                        return advance as f64 * math_scale;
-                    }
-                }
-            }
-            // 4. Try Symbola Fallback
-            if let Some(glyph_id) = symbola_face.glyph_index(c) {
-                if glyph_id.0 != 0 {
-                    if let Some(advance) = symbola_face.glyph_hor_advance(glyph_id) {
-                        return advance as f64 * symbola_scale;
-                    }
-                }
-            }
-            
-            // 5. Ultimate Fallback for unmapped characters (e.g., Thai)
-            24.0 
-        }).sum()
-    };
+    // Font stacks exactly as leaderboard.svg sets them, so measured widths match the raster.
+    let label_css = state.fonts.stack("leaderboard", "label");
+    let label_font = FontStack::parse(label_css).bold();
+    let measure_text = |text: &str, font: &FontStack| -> f64 { state.text.measure(text, font, 30.0) as f64 };
 
-    for (i, user) in payload.users.into_iter().enumerate() {
-        let avatar_b64 = avatars_b64[i].clone();
+    for ((user, avatar), name) in payload.users.into_iter().zip(avatars).zip(names) {
+        layers.push(RasterLayer::new(avatar, 10.0, y_pos as f32, 57.0, 58.0).clip(Clip::RoundedRect(10.0)));
 
         let is_highlighted = payload.highlight_user_id.as_ref() == Some(&user.user_id);
         
         // 1. Measure precise widths
         let rank_text = format!("#{}", user.rank);
-        let rank_width = measure_text(&rank_text);
-        let separator_width = measure_text("|");
+        let rank_width = measure_text(&rank_text, &label_font);
+        let separator_width = measure_text("|", &label_font);
         
         // 2. Calculate horizontal positions dynamically with EXACT 20px gaps
         let rank_x_start = 75.0; // Fixed start past avatar
@@ -331,10 +508,16 @@ pub async fn render_leaderboard(
 
         // Measure the xp width
         let xp_str = format!("XP: {} pts", format_xp(user.xp));
-        let xp_width = measure_text(&xp_str);
-
-        // Emoji total width
-        let emoji_count = user.emojis.len();
+        let xp_width = measure_text(&xp_str, &label_font);
+
+        // Emoji total width; trailing emoji beyond half the space before the XP
+        // label are dropped, so the name always keeps room
+        let room = 775.0 - xp_width - 18.0 - separator_width - 20.0 - username_x_start;
+        let mut emoji_images = Vec::new();
+        for emoji in &user.emojis {
+            emoji_images.extend(emoji_image(state, &emoji.hex, fallbacks).await);
+        }
+        let emoji_count = emoji_images.len().min(((room / 2.0 - 1.0) / 37.0).max(0.0) as usize);
         let emoji_total_width = if emoji_count > 0 {
             (emoji_count as f64) * 30.0 + ((emoji_count - 1) as f64) * 7.0 + 8.0 
         } else {
@@ -344,40 +527,25 @@ pub async fn render_leaderboard(
         let max_content_end = 775.0 - xp_width - 18.0 - separator_width - 20.0 - emoji_total_width;
         let max_username_width = max_content_end - username_x_start;
 
-        // Normalize fancy fonts before measuring or rendering
-        let mut display_username = normalize_discord_name(&user.username);
-
-        let mut username_width = measure_text(&display_username);
-
-        if username_width > max_username_width && max_username_width > 0.0 {
-            let mut chars: Vec<char> = display_username.chars().collect();
-            while username_width > max_username_width && !chars.is_empty() {
-                chars.pop();
-                display_username = format!("{}...", chars.iter().collect::<String>());
-                username_width = measure_text(&display_username);
-            }
-        }
+        let name = layout_name(
+            &state.fonts,
+            &state.text,
+            name,
+            "leaderboard",
+            30.0,
+            username_x_start,
+            max_username_width.max(0.0) as f32,
+        );
+        let username_width = name.width as f64;
+        let emoji_y = (y_pos + 15) as f32;
+        layers.extend(NameLayout::emoji_layers(name.images, emoji_y, LEADERBOARD_EMOJI.width as f32));
 
-        // Generate template_emojis with x_offset calculated dynamically!
-        let mut template_emojis = Vec::new();
+        // Trailing emoji, placed right after the name
         let mut current_emoji_x = username_x_start + username_width + 8.0;
 
-        for emoji in user.emojis {
-            let mut path = format!("./assets/emojis/{}.png", emoji.hex);
-            if !std::path::Path::new(&path).exists() {
-                path = format!("../assets/emojis/{}.png", emoji.hex);
-            }
-            let b64 = match tokio::fs::read(&path).await {
-                Ok(bytes) => to_png_b64(&bytes),
-                Err(_) => "".to_string(),
-            };
-            if !b64.is_empty() {
-                template_emojis.push(crate::template::TemplateEmojiData {
-                    b64,
-                    x_offset: current_emoji_x,
-                });
-                current_emoji_x += 37.0; // 30 size + 7 gap
-            }
+        for image in emoji_images.into_iter().take(emoji_count) {
+            layers.push(RasterLayer::new(image, current_emoji_x as f32, emoji_y, 30.0, 30.0));
+            current_emoji_x += 37.0; // 30 size + 7 gap
         }
 
         // End of the content block (username + emojis)
@@ -387,14 +555,10 @@ pub async fn render_leaderboard(
         let separator2_x_start = content_end_x + 20.0;
         let xp_x_start = separator2_x_start + separator_width + 18.0;
 
-        let use_system_font = requires_system_font(&display_username);
-
         template_users.push(crate::template::TemplateUserData {
-            username: display_username,
-            avatar_b64,
+            name_runs: name.runs,
             rank: user.rank,
             formatted_xp: format_xp(user.xp),
-            emojis: template_emojis,
             rank_x_start,
             separator_x_start,
             username_x_start,
@@ -402,7 +566,7 @@ pub async fn render_leaderboard(
             bg_color: get_bg_color(user.rank, is_highlighted),
             y_pos,
             xp_x_start,
-            use_system_font,
+            username_font: state.fonts.stack("leaderboard", "username").to_string(),
         });
 
         y_pos += 60;
@@ -413,44 +577,17 @@ pub async fn render_leaderboard(
     let template = crate::template::LeaderboardTemplate {
         users: template_users,
         height,
+        label_font: label_css.to_string(),
     };
 
-    let svg_string = match template.render() {
-        Ok(s) => s,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to render template: {}", e)).into_response(),
-    };
-
-    let mut opt = Options::default();
-    opt.font_family = "Poppins, DejaVu Sans, Noto Color Emoji, Noto Sans Math, Noto Sans Arabic, Symbola, sans-serif".to_string();
-
-    let mut rtree = match Tree::from_str(&svg_string, &opt) {
-        Ok(tree) => tree,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to parse SVG: {}", e)).into_response(),
-    };
-    
-    rtree.postprocess(usvg::PostProcessingSteps::default(), &state.fontdb);
-
-    let mut pixmap = match Pixmap::new(800, height as u32) {
-        Some(p) => p,
-        None => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to allocate pixmap").into_response(),
-    };
-    
-    resvg::render(&rtree, usvg::Transform::default(), &mut pixmap.as_mut());
-
-    let png_bytes = match pixmap.encode_png() {
-        Ok(bytes) => bytes,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode PNG").into_response(),
-    };
-
-    let duration = start.elapsed().as_secs_f64();
-    tracing::debug!("Recording leaderboard render duration: {}s", duration);
-    metrics::histogram!("renderer_leaderboard_render_duration_seconds").record(duration);
-
-    (
-        StatusCode::OK,
-        [(axum::http::header::CONTENT_TYPE, "image/png")],
-        png_bytes,
-    ).into_response()
+    Ok(RenderJob {
+        svg: state.templates.render(&template)?,
+        font_family: state.fonts.stack("leaderboard", "default").to_string(),
+        canvas: Pixmap::new(800, height as u32).ok_or(RenderError::Allocation(800, height as u32))?,
+        layers,
+        format,
+        output: payload.output.clone(),
+    })
 }
 
 // =============================================================================
@@ -462,66 +599,68 @@ pub async fn render_leaderboard(
 ///   role_announcement_template.png + circle-clipped icon + role name text
 pub async fn render_role_reward_base(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<RoleRewardBaseRequest>,
-) -> Response {
+    headers: HeaderMap,
+    payload: Result<Json<RoleRewardBaseRequest>, JsonRejection>,
+) -> Result<Response, RenderError> {
     let start = Instant::now();
+    let Json(payload) = payload?;
+    payload.validate()?;
+    let format = resolve_format(&payload.output, &headers)?;
 
-    // 1. Load template PNG from disk
-    let template_bytes = {
-        let mut buf = tokio::fs::read("./assets/role template/role_announcement_template.png").await;
-        if buf.is_err() {
-            buf = tokio::fs::read("../assets/role template/role_announcement_template.png").await;
-        }
-        match buf {
-            Ok(b) => b,
-            Err(e) => {
-                tracing::error!("Failed to read role template PNG: {}", e);
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Missing role template PNG").into_response();
+    let key = state.output_cache.key("role_reward", &payload, format.content_type());
+    if let Some(response) = cached_output(&state, &headers, "role_reward", &key).await {
+        return Ok(response);
+    }
+
+    let fallbacks = Fallbacks::default();
+    let job = prepare_role_reward_base(&state, payload, format, &fallbacks).await?;
+    let image_bytes = state.render_pool.rasterize(job, state.fonts.db()).await?;
+
+    let duration = start.elapsed().as_secs_f64();
+    metrics::histogram!("renderer_role_reward_base_duration_seconds").record(duration);
+    tracing::debug!("Role reward base rendered in {:.3}s", duration);
+
+    Ok(store_output(&state, "role_reward", key, format.content_type(), image_bytes, &fallbacks).await)
+}
+
+/// The role announcement template PNG, read and decoded on first use. A failed
+/// load is not remembered, so the next request tries again.
+async fn role_template(state: &AppState) -> Result<Arc<Pixmap>, RenderError> {
+    state
+        .role_template
+        .get_or_try_init(|| async {
+            let mut buf = tokio::fs::read("./assets/role template/role_announcement_template.png").await;
+            if buf.is_err() {
+                buf = tokio::fs::read("../assets/role template/role_announcement_template.png").await;
             }
-        }
-    };
+            let bytes = buf.map_err(|source| RenderError::Asset { what: "role template PNG", source })?;
+            let pixmap = Pixmap::decode_png(&bytes)
+                .map_err(|e| RenderError::Decode(format!("role template PNG: {}", e)))?;
+            Ok(Arc::new(pixmap))
+        })
+        .await
+        .cloned()
+}
 
-    // Decode the template to get its actual dimensions
-    let template_pixmap = match Pixmap::decode_png(&template_bytes) {
-        Ok(p) => p,
-        Err(e) => {
-            tracing::error!("Failed to decode role template PNG: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to decode role template PNG").into_response();
-        }
-    };
+async fn prepare_role_reward_base(
+    state: &AppState,
+    payload: RoleRewardBaseRequest,
+    format: OutputFormat,
+    fallbacks: &Fallbacks,
+) -> Result<RenderJob, RenderError> {
+    // 1. The decoded template PNG is the canvas
+    let template_pixmap = role_template(state).await?;
     let canvas_width  = template_pixmap.width();
     let canvas_height = template_pixmap.height();
 
-    let template_b64 = general_purpose::STANDARD.encode(&template_bytes);
-
-    // 2. Fetch the role icon (if provided)
-    let icon_b64 = if let Some(ref url) = payload.icon_url {
-        if !url.is_empty() {
-            if let Some(cached) = state.avatar_cache.get(url).await {
-                cached
-            } else {
-                let client = Client::new();
-                match client.get(url).send().await {
-                    Ok(res) if res.status().is_success() => {
-                        let bytes = res.bytes().await.unwrap_or_default().to_vec();
-                        if bytes.is_empty() {
-                            String::new()
-                        } else {
-                            let b64 = to_png_b64(&bytes);
-                            if !b64.is_empty() {
-                                state.avatar_cache.insert(url.clone(), b64.clone()).await;
-                            }
-                            b64
-                        }
-                    }
-                    _ => String::new(),
-                }
-            }
-        } else {
-            String::new()
+    // 2. Fetch the role icon (if provided); an empty or failing URL gets a default avatar
+    let icon_size = payload.icon_size.unwrap_or(DEFAULT_ROLE_ICON_SIZE);
+    let icon = match payload.icon_url.as_deref() {
+        Some(url) => {
+            let seed = AvatarSeed { user_id: None, discriminator: None, name: payload.role_name.as_deref().unwrap_or("") };
+            Some(avatar_or_default(state, url, ImageBox::contain(icon_size, icon_size), seed, fallbacks).await)
         }
-    } else {
-        String::new()
+        None => None,
     };
 
     // Canvas is 3041x894. We want a large left avatar and nicely stacked text next to it.
@@ -529,90 +668,500 @@ pub async fn render_role_reward_base(
     // Text: nicely stacked next to the avatar. x=950.
     let icon_x = payload.icon_x.unwrap_or(180);
     let icon_y = payload.icon_y.unwrap_or(147);
-    let icon_size = payload.icon_size.unwrap_or(600);
 
-    let role_name = payload.role_name.clone().unwrap_or_else(|| "HOMOSAPIEN".to_string());
-    let font_size = payload.font_size.unwrap_or(190);
+    let role_name = collapse_whitespace(payload.role_name.as_deref().unwrap_or("HOMOSAPIEN"));
+    let requested_size = payload.font_size.unwrap_or(190);
     let text_x = payload.text_x.unwrap_or(885);
     let text_y = payload.text_y.unwrap_or(500);
 
-    let mut template_emojis = Vec::new();
-    let mut current_emoji_x = text_x as f64;
-    let emoji_size = font_size as f64;
-
+    let mut emoji_images = Vec::new();
     if let Some(emojis) = &payload.emojis {
         for emoji in emojis {
-            let mut path = format!("./assets/emojis/{}.png", emoji.hex);
-            if !std::path::Path::new(&path).exists() {
-                path = format!("../assets/emojis/{}.png", emoji.hex);
-            }
-            let b64 = match tokio::fs::read(&path).await {
-                Ok(bytes) => to_png_b64(&bytes),
-                Err(_) => "".to_string(),
-            };
-            if !b64.is_empty() {
-                template_emojis.push(crate::template::TemplateEmojiData {
-                    b64,
-                    x_offset: current_emoji_x,
-                });
-                current_emoji_x += emoji_size + 15.0;
-            }
+            emoji_images.extend(emoji_image(state, &emoji.hex, fallbacks).await);
         }
     }
 
-    let text_x_after_emojis = current_emoji_x as u32;
+    // 3. Fit the emoji row + role name into the text box
+    let role_css = state.fonts.stack("role_reward_base", "role_name");
+    let name_font = FontStack::parse(role_css).bold();
+    let box_width = match payload.max_text_width {
+        Some(width) => width as f32,
+        None => canvas_width as f32 - text_x as f32 - ROLE_NAME_RIGHT_MARGIN,
+    }
+    .max(0.0);
+    // Each emoji is drawn at the font size and followed by a 15px gap; emoji beyond
+    // half the box at the size they are drawn at are dropped, so the name keeps room.
+    let emoji_cap = |size: u32| (box_width / 2.0 / (size as f32 + 15.0)) as usize;
+    let font_size = match payload.fit {
+        Some(TextFit::Shrink) => {
+            // The row may end up at the minimum size, so keep what fits there;
+            // the size is then solved for the row and the name together.
+            emoji_images.truncate(emoji_cap(MIN_ROLE_FONT_SIZE.min(requested_size)));
+            let unit_width = state.text.measure(&role_name, &name_font, 1.0);
+            shrink_font_size(box_width, emoji_images.len() as f32, unit_width, requested_size)
+        }
+        _ => {
+            emoji_images.truncate(emoji_cap(requested_size));
+            requested_size
+        }
+    };
+    let emoji_count = emoji_images.len() as f32;
+    let emoji_row_width = |size: u32| emoji_count * (size as f32 + 15.0);
+    let name_width = (box_width - emoji_row_width(font_size)).max(0.0);
+
+    let lines = match payload.fit {
+        Some(TextFit::Wrap) => state.text.wrap_two_lines(&role_name, &name_font, font_size as f32, name_width, box_width),
+        // With `shrink`, only a name too long even at the minimum size still gets an ellipsis.
+        _ => vec![state.text.ellipsize(&role_name, &name_font, font_size as f32, name_width).0],
+    };
+
+    // Two lines are centred on the requested baseline; the first sits after the
+    // emoji row, the second starts under it.
+    let line_height = font_size as f64 * 1.2;
+    let first_baseline = text_y as f64 - (lines.len() - 1) as f64 * line_height / 2.0;
+    let emoji_size = font_size as f64;
+    // Each line is split into bidi runs placed in visual order, so a
+    // right-to-left or mixed-direction role name reads correctly.
+    let role_lines = lines
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, text)| {
+            let line_x = if i == 0 { text_x as f64 + emoji_row_width(font_size) as f64 } else { text_x as f64 };
+            let y = first_baseline + i as f64 * line_height;
+            bidi_line(&state.text, text, role_css, font_size as f32)
+                .into_iter()
+                .map(move |(text, offset)| TemplateTextLine { text, x: line_x + offset as f64, y })
+        })
+        .collect();
+
+    // Emoji row sits on the first line, sized and aligned to the chosen font size.
+    let emoji_y = first_baseline - emoji_size + (emoji_size * 0.15);
+    let layers = icon
+        .map(|image| RasterLayer::new(image, icon_x as f32, icon_y as f32, icon_size as f32, icon_size as f32))
+        .into_iter()
+        .chain(emoji_images.into_iter().enumerate().map(|(i, image)| {
+            let x = text_x as f64 + i as f64 * (emoji_size + 15.0);
+            RasterLayer::new(image, x as f32, emoji_y as f32, emoji_size as f32, emoji_size as f32)
+        }))
+        .collect();
 
     let template = RoleRewardBaseTemplate {
-        template_b64,
-        icon_b64,
-        role_name,
-        emojis: template_emojis,
+        role_lines,
+        role_font: role_css.to_string(),
         role_color: payload.role_color.clone(),
         canvas_width,
         canvas_height,
-        icon_x,
-        icon_y,
-        icon_size,
-        text_x: text_x_after_emojis,
-        text_y, // Pulls the bottom line up significantly
         font_size,
-        emoji_y: text_y as f64 - font_size as f64 + (font_size as f64 * 0.15),
     };
 
-    let svg_string = match template.render() {
-        Ok(s) => s,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("SVG template error: {}", e)).into_response(),
-    };
+    // 4. Hand off to the rasterizer, drawing onto a copy of the template PNG
+    Ok(RenderJob {
+        svg: state.templates.render(&template)?,
+        font_family: state.fonts.stack("role_reward_base", "default").to_string(),
+        canvas: template_pixmap.as_ref().clone(),
+        layers,
+        format,
+        output: payload.output.clone(),
+    })
+}
+
+
+// =============================================================================
+// Clan GIF Compositor
+// =============================================================================
+
+/// Reads every PNG in `assets/gif_templates/<template>/frames`, sorted by filename.
+async fn load_gif_frames(template: &str) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut dir = PathBuf::from("./assets/gif_templates").join(template).join("frames");
+    if !dir.exists() {
+        dir = PathBuf::from("../assets/gif_templates").join(template).join("frames");
+    }
 
-    // 4. Render SVG → PNG
-    let mut opt = Options::default();
-     opt.font_family = "Poppins, DejaVu Sans, Noto Color Emoji, Noto Sans Math, Symbola, sans-serif".to_string();
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    let mut paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut frames = Vec::with_capacity(paths.len());
+    for path in paths {
+        frames.push(tokio::fs::read(path).await?);
+    }
+    Ok(frames)
+}
+
+/// CPU-bound half of the clan GIF: icon resize, per-frame compositing, GIF or
+/// animated WebP encoding.
+fn composite_clan_animation(
+    frame_bytes: &[Vec<u8>],
+    icons: &[Option<Arc<Pixmap>>],
+    payload: &ClanGifRequest,
+    format: AnimationFormat,
+) -> Result<Vec<u8>, RenderError> {
+    // Icons arrive sized to fit the icon box; stretch any that are not square to
+    // fill it. A missing icon simply isn't drawn.
+    let icon_size = payload.icon_size.unwrap_or(DEFAULT_CLAN_ICON_SIZE);
+    let icons: Vec<Option<Arc<Pixmap>>> = icons
+        .iter()
+        .map(|icon| {
+            let icon = icon.as_ref()?;
+            if icon.width() == icon_size && icon.height() == icon_size {
+                return Some(icon.clone());
+            }
+            let rgba = render::pixmap_to_rgba(icon);
+            let resized = image::imageops::resize(&rgba, icon_size, icon_size, image::imageops::FilterType::Lanczos3);
+            render::rgba_to_pixmap(&resized).map(Arc::new)
+        })
+        .collect();
+
+    // Composite each frame and stream it into the encoder
+    let delay_ms = payload.frame_delay_ms.unwrap_or(100);
+    let mut out = Vec::new();
+    let webp = {
+        let mut encoder = match format {
+            AnimationFormat::Gif => {
+                let mut gif = GifEncoder::new_with_speed(&mut out, 10);
+                gif.set_repeat(Repeat::Infinite)?;
+                AnimationEncoder::Gif(gif)
+            }
+            AnimationFormat::WebP => AnimationEncoder::WebP(None),
+        };
+
+        for (n, bytes) in frame_bytes.iter().enumerate() {
+            let mut frame = Pixmap::decode_png(bytes)
+                .map_err(|e| RenderError::Decode(format!("GIF template frame {}: {}", n, e)))?;
+
+            for (clan_idx, clan) in payload.clans.iter().enumerate() {
+                let Some(icon) = &icons[clan_idx] else { continue };
+                let visible = clan.visible.iter().any(|[from, to]| (*from..=*to).contains(&(n as u32)));
+                if !visible {
+                    continue;
+                }
+                let Some(coord) = payload.coords.get(n).and_then(|frame| frame.get(clan_idx)) else {
+                    continue;
+                };
+                frame.draw_pixmap(coord.x, coord.y, Pixmap::as_ref(icon), &PixmapPaint::default(), Transform::identity(), None);
+            }
+
+            let rgba = render::pixmap_to_rgba(&frame);
+            match &mut encoder {
+                AnimationEncoder::Gif(gif) => {
+                    gif.encode_frame(Frame::from_parts(rgba, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1)))?;
+                }
+                AnimationEncoder::WebP(webp) => {
+                    let webp = webp.get_or_insert_with(|| AnimatedWebP::new(rgba.width(), rgba.height()));
+                    if rgba.dimensions() != (webp.width(), webp.height()) {
+                        return Err(RenderError::Decode(format!("GIF template frame {}: size differs from frame 0", n)));
+                    }
+                    webp.add_frame(&rgba, delay_ms)?;
+                }
+            }
+        }
 
-    let mut rtree = match Tree::from_str(&svg_string, &opt) {
-        Ok(t) => t,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("SVG parse error: {}", e)).into_response(),
+        match encoder {
+            AnimationEncoder::WebP(Some(webp)) => Some(webp.finish()),
+            _ => None,
+        }
     };
-    rtree.postprocess(usvg::PostProcessingSteps::default(), &state.fontdb);
 
-    let mut pixmap = match Pixmap::new(canvas_width, canvas_height) {
-        Some(p) => p,
-        None => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to allocate pixmap").into_response(),
+    Ok(webp.unwrap_or(out))
+}
+
+/// Where `composite_clan_animation` sends frames. The WebP muxer is created
+/// with the first frame, whose size becomes the canvas.
+enum AnimationEncoder<W: Write> {
+    Gif(GifEncoder<W>),
+    WebP(Option<AnimatedWebP>),
+}
+
+/// POST /render/clan-gif
+/// Composites clan icons onto a template frame sequence and returns an animated
+/// GIF, or an animated WebP with `format: "webp"`.
+/// Replaces the ffmpeg overlay/motion-expression pipeline in gifWorker.js:
+///   - icon position per frame comes straight from coords[frame][clan]
+///   - an icon is drawn only on frames inside one of its `visible` ranges
+pub async fn render_clan_gif(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    payload: Result<Json<ClanGifRequest>, JsonRejection>,
+) -> Result<Response, RenderError> {
+    let start = Instant::now();
+    let Json(payload) = payload?;
+    payload.validate()?;
+
+    let format = payload.format.as_deref().and_then(AnimationFormat::from_name).unwrap_or(AnimationFormat::Gif);
+    let key = state.output_cache.key("clan_gif", &payload, format.content_type());
+    if let Some(response) = cached_output(&state, &headers, "clan_gif", &key).await {
+        return Ok(response);
+    }
+
+    // 1. Load the template frame sequence
+    let frame_bytes = match load_gif_frames(&payload.template).await {
+        Ok(frames) if !frames.is_empty() => frames,
+        _ => return Err(RenderError::NotFound(format!("GIF template '{}'", payload.template))),
     };
-    resvg::render(&rtree, usvg::Transform::default(), &mut pixmap.as_mut());
 
-    let png_bytes = match pixmap.encode_png() {
-        Ok(b) => b,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode PNG").into_response(),
+    // 2. Fetch all clan icons concurrently (shared avatar cache)
+    let icon_size = payload.icon_size.unwrap_or(DEFAULT_CLAN_ICON_SIZE);
+    let clan_icon_box = ImageBox::contain(icon_size, icon_size);
+    let fallbacks = Fallbacks::default();
+    let icon_futures = payload
+        .clans
+        .iter()
+        .map(|clan| fetch_image(&state, clan.icon_url.as_deref().unwrap_or(""), clan_icon_box, &fallbacks));
+    let icons = futures::future::join_all(icon_futures).await;
+
+    // 3. Decode, composite and encode on the render pool
+    let frame_count = frame_bytes.len();
+    let gif_bytes = state
+        .render_pool
+        .run(move || composite_clan_animation(&frame_bytes, &icons, &payload, format))
+        .await??;
+
+    let duration = start.elapsed().as_secs_f64();
+    metrics::histogram!("renderer_clan_gif_duration_seconds").record(duration);
+    tracing::debug!("Clan GIF ({} frames) rendered in {:.3}s", frame_count, duration);
+
+    Ok(store_output(&state, "clan_gif", key, format.content_type(), gif_bytes, &fallbacks).await)
+}
+
+// =============================================================================
+// Batch Rendering
+// =============================================================================
+
+/// Every remote image a job will pull in and the box it is drawn at, so the
+/// batch can fetch and size each one once.
+fn job_images(job: &BatchJobKind) -> Vec<(String, ImageBox)> {
+    let custom_emoji = |runs: &Option<Vec<NameRun>>, target: ImageBox| -> Vec<(String, ImageBox)> {
+        runs.iter().flatten().filter_map(NameRun::custom_emoji_url).map(|url| (url, target)).collect()
     };
+    match job {
+        BatchJobKind::RankCard(p) => std::iter::once((p.avatar_url.clone(), RANK_CARD_AVATAR))
+            .chain(p.theme.background_image_url.clone().map(|url| (url, RANK_CARD_BACKGROUND)))
+            .chain(custom_emoji(&p.username_runs, RANK_CARD_EMOJI))
+            .collect(),
+        BatchJobKind::Leaderboard(p) => p
+            .users
+            .iter()
+            .flat_map(|u| {
+                std::iter::once((u.avatar_url.clone(), LEADERBOARD_AVATAR))
+                    .chain(custom_emoji(&u.username_runs, LEADERBOARD_EMOJI))
+            })
+            .collect(),
+        BatchJobKind::RoleReward(p) => {
+            let size = p.icon_size.unwrap_or(DEFAULT_ROLE_ICON_SIZE);
+            p.icon_url.clone().map(|url| (url, ImageBox::contain(size, size))).into_iter().collect()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchManifestEntry {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+type BatchResult = (String, OutputFormat, Result<Vec<u8>, RenderError>);
+
+/// Packs rendered jobs as `<id>.<ext>` plus a `manifest.json` describing every job.
+fn write_batch_archive(results: Vec<BatchResult>) -> zip::result::ZipResult<Vec<u8>> {
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut manifest = Vec::with_capacity(results.len());
+
+    for (id, format, result) in results {
+        match result {
+            Ok(bytes) => {
+                let file = format!("{}.{}", id, format.extension());
+                zip.start_file(file.as_str(), options)?;
+                zip.write_all(&bytes)?;
+                manifest.push(BatchManifestEntry {
+                    id,
+                    file: Some(file),
+                    content_type: Some(format.content_type()),
+                    error: None,
+                });
+            }
+            Err(e) => manifest.push(BatchManifestEntry {
+                id,
+                file: None,
+                content_type: None,
+                error: Some(e.into()),
+            }),
+        }
+    }
+
+    zip.start_file("manifest.json", options)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest).map_err(std::io::Error::from)?;
+    Ok(zip.finish()?.into_inner())
+}
+
+/// POST /render/batch
+/// Renders many heterogeneous jobs (rank cards, leaderboards, role rewards) in one
+/// call and returns a ZIP with one `<id>.<ext>` per successful job and a
+/// `manifest.json` carrying the `{code, message, stage}` error of any job that failed.
+///   - each distinct avatar/icon is fetched and sized once for the whole batch
+///   - rasterization runs on the shared render pool, at most pool-size jobs at a time
+pub async fn render_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    payload: Result<Json<BatchRequest>, JsonRejection>,
+) -> Result<Response, RenderError> {
+    let start = Instant::now();
+    let Json(payload) = payload?;
+    payload.validate()?;
+
+    // Resolve every output format first so a bad one rejects the batch before any
+    // work. Keys match the single-job routes, so batches and singles share renders.
+    let keyed = payload
+        .jobs
+        .iter()
+        .map(|job| {
+            let cache = &state.output_cache;
+            let (route, format, key) = match &job.job {
+                BatchJobKind::RankCard(p) => {
+                    let format = resolve_format(&p.output, &headers)?;
+                    ("rank_card", format, cache.key("rank_card", p, format.content_type()))
+                }
+                BatchJobKind::Leaderboard(p) => {
+                    let format = resolve_format(&p.output, &headers)?;
+                    ("leaderboard", format, cache.key("leaderboard", p, format.content_type()))
+                }
+                BatchJobKind::RoleReward(p) => {
+                    let format = resolve_format(&p.output, &headers)?;
+                    ("role_reward", format, cache.key("role_reward", p, format.content_type()))
+                }
+            };
+            Ok((route, format, key))
+        })
+        .collect::<Result<Vec<_>, RenderError>>()?;
+
+    // Jobs already in the output cache skip fetching and rendering entirely.
+    let mut cached = Vec::with_capacity(keyed.len());
+    for (route, _, key) in &keyed {
+        cached.push(state.output_cache.get(route, key).await);
+    }
+
+    // 1. Warm avatar_cache with each distinct image exactly once
+    let images: HashSet<(String, ImageBox)> = payload
+        .jobs
+        .iter()
+        .zip(&cached)
+        .filter(|(_, hit)| hit.is_none())
+        .flat_map(|(job, _)| job_images(&job.job))
+        .filter(|(url, _)| !url.is_empty())
+        .collect();
+    // Failures here only warm the negative cache; each job records its own below.
+    let warmup = Fallbacks::default();
+    futures::future::join_all(images.iter().map(|(url, target)| fetch_image(&state, url, *target, &warmup))).await;
+
+    // 2. Layout + templating (every fetch is now a cache hit), then rasterize on the
+    //    render pool. A batch never holds more queue places than the pool has
+    //    workers, so one big batch cannot starve single renders. The permit is
+    //    taken before preparing, since preparing allocates the job's canvas
+    //    (~11 MB for a role reward): a batch holds at most one canvas per worker.
+    let permits = Arc::new(Semaphore::new(state.render_pool.size()));
+    let jobs = payload.jobs.into_iter().zip(keyed).zip(cached);
+    let results: Vec<BatchResult> = futures::future::join_all(jobs.map(|((job, (_, format, key)), hit)| {
+        let state = &state;
+        let permits = permits.clone();
+        async move {
+            if let Some(hit) = hit {
+                return (job.id, format, Ok(hit.bytes.to_vec()));
+            }
+            let _permit = permits.acquire_owned().await.expect("batch semaphore is never closed");
+            let fallbacks = Fallbacks::default();
+            let prepared = match job.job {
+                BatchJobKind::RankCard(p) => prepare_rank_card(state, p, format, &fallbacks).await,
+                BatchJobKind::Leaderboard(p) => prepare_leaderboard(state, p, format, &fallbacks).await,
+                BatchJobKind::RoleReward(p) => prepare_role_reward_base(state, p, format, &fallbacks).await,
+            };
+            let result = match prepared {
+                Ok(render_job) => state.render_pool.rasterize(render_job, state.fonts.db()).await,
+                Err(e) => Err(e),
+            };
+            match &result {
+                Ok(_) if fallbacks.any() => {
+                    metrics::counter!("renderer_output_uncached_total", "route" => "batch").increment(1);
+                }
+                Ok(bytes) => {
+                    state.output_cache.insert(key, format.content_type(), bytes.clone()).await;
+                }
+                Err(_) => {}
+            }
+            (job.id, format, result)
+        }
+    }))
+    .await;
+
+    let failed = results.iter().filter(|(_, _, r)| r.is_err()).count();
+    metrics::counter!("renderer_batch_jobs_total", "status" => "ok").increment((results.len() - failed) as u64);
+    metrics::counter!("renderer_batch_jobs_total", "status" => "error").increment(failed as u64);
+
+    // 3. Package
+    let job_count = results.len();
+    let archive = write_batch_archive(results)?;
 
     let duration = start.elapsed().as_secs_f64();
-    metrics::histogram!("renderer_role_reward_base_duration_seconds").record(duration);
-    tracing::debug!("Role reward base rendered in {:.3}s", duration);
+    metrics::histogram!("renderer_batch_duration_seconds").record(duration);
+    tracing::debug!("Batch of {} jobs ({} failed) rendered in {:.3}s", job_count, failed, duration);
 
-    (
+    Ok((
         StatusCode::OK,
-        [(axum::http::header::CONTENT_TYPE, "image/png")],
-        png_bytes,
-    ).into_response()
+        [(axum::http::header::CONTENT_TYPE, "application/zip")],
+        archive,
+    ).into_response())
 }
 
+// =============================================================================
+// Fonts
+// =============================================================================
+
+/// GET /fonts
+///
+/// Loaded font families with their script coverage, and the named stacks.
+pub async fn list_fonts(State(state): State<Arc<AppState>>) -> Json<crate::fonts::FontsInfo> {
+    Json(state.fonts.info().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrink_keeps_requested_size_when_it_fits() {
+        assert_eq!(shrink_font_size(2000.0, 0.0, 5.0, 190), 190);
+    }
+
+    #[test]
+    fn shrink_fits_name_and_emoji_row() {
+        // 2 * (size + 15) + 10 * size <= 1230 → size = 100
+        assert_eq!(shrink_font_size(1230.0, 2.0, 10.0, 190), 100);
+    }
+
+    #[test]
+    fn shrink_stops_at_minimum_size() {
+        assert_eq!(shrink_font_size(100.0, 0.0, 10.0, 190), MIN_ROLE_FONT_SIZE);
+    }
+
+    #[test]
+    fn shrink_never_enlarges_a_size_below_the_minimum() {
+        assert_eq!(shrink_font_size(2000.0, 0.0, 5.0, 20), 20);
+        assert_eq!(shrink_font_size(10.0, 3.0, 10.0, 20), 20);
+    }
+
+    #[test]
+    fn shrink_handles_empty_name_and_no_emoji() {
+        assert_eq!(shrink_font_size(500.0, 0.0, 0.0, 190), 190);
+        assert_eq!(shrink_font_size(0.0, 0.0, 0.0, 8), 8);
+    }
+}
@@ -1,6 +1,19 @@
+mod cache;
+mod color_emoji;
+mod default_avatar;
+mod disk_cache;
+mod emoji;
+mod error;
+mod fetcher;
+mod fonts;
 mod handler;
 mod models;
+mod normalize;
+mod output;
+mod render;
 mod template;
+mod template_engine;
+mod text;
 mod state;
 
 use axum::{
@@ -15,9 +28,6 @@ use tokio::signal;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use metrics_exporter_prometheus::PrometheusBuilder;
-use askama::Template;
-use usvg::{Options, Tree, TreeParsing, TreePostProc};
-use tiny_skia::Pixmap;
 
 // Use jemalloc to prevent memory fragmentation in long-running service
 #[global_allocator]
@@ -25,24 +35,6 @@ static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 use crate::state::AppState;
 
-/// Render the static rank card background SVG into a Pixmap exactly once.
-fn build_rank_card_bg(fontdb: &usvg::fontdb::Database) -> anyhow::Result<Pixmap> {
-    let svg_string = crate::template::RankCardBackgroundTemplate {}.render()?;
-
-    let mut opt = Options::default();
-    opt.font_family = "Poppins, DejaVu Sans, sans-serif".to_string();
-
-    let mut rtree = Tree::from_str(&svg_string, &opt)
-        .map_err(|e| anyhow::anyhow!("Failed to parse rank_card_bg SVG: {}", e))?;
-    rtree.postprocess(usvg::PostProcessingSteps::default(), fontdb);
-
-    let mut pixmap = Pixmap::new(800, 250)
-        .ok_or_else(|| anyhow::anyhow!("Failed to allocate rank_card_bg pixmap"))?;
-    resvg::render(&rtree, usvg::Transform::default(), &mut pixmap.as_mut());
-
-    Ok(pixmap)
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Initialize tracing with environment variable support
@@ -57,49 +49,74 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Starting Renderer Microservice (SVG Edition)...");
     tracing::info!("Using jemalloc for memory management");
 
-    let mut fontdb = usvg::fontdb::Database::new();
-    fontdb.load_system_fonts();
-    tracing::info!("Loaded system fonts.");
-    let font_data = include_bytes!("../../assets/fonts/Poppins-Bold.ttf");
-    fontdb.load_font_data(font_data.to_vec());
-    tracing::info!("Loaded Poppins-Bold font.");
-    let symbola_data = include_bytes!("../../assets/fonts/Symbola.ttf");
-    fontdb.load_font_data(symbola_data.to_vec());
-    tracing::info!("Loaded Symbola font.");
-
-    // ADD THESE LINES: Load the math font explicitly
-    let math_data = include_bytes!("../../assets/fonts/NotoSansMath-Regular.ttf");
-    fontdb.load_font_data(math_data.to_vec());
-    tracing::info!("Loaded Noto Sans Math font.");
-
-    // Pre-bake the static rank card background once.
-    tracing::info!("Pre-baking rank card background...");
-    let rank_card_bg = build_rank_card_bg(&fontdb)?;
-    tracing::info!("Rank card background cached ({} bytes).", rank_card_bg.data().len());
+    // Initialize Prometheus recorder before anything records metrics
+    let builder = PrometheusBuilder::new();
+    let handle = builder
+        .install_recorder()
+        .expect("failed to install Prometheus recorder");
+
+    let fonts = crate::fonts::FontRegistry::from_env()?;
 
-    let avatar_cache = moka::future::Cache::builder()
-        .time_to_live(std::time::Duration::from_secs(15 * 60))
-        .max_capacity(1000)
+    let avatar_cache = crate::cache::AvatarCache::from_env();
+    let disk_cache = crate::disk_cache::DiskCache::from_env();
+    let default_avatars = crate::default_avatar::DefaultAvatars::from_env(fonts.db());
+
+    let emojis = crate::emoji::EmojiStore::from_env()?;
+    let color_emoji = crate::color_emoji::ColorEmojiFont::from_env();
+
+    let templates = crate::template_engine::TemplateEngine::from_env()?;
+    tracing::info!("Templates ready (version {}).", templates.version());
+
+    // One pre-baked background per distinct theme (~800 KB each).
+    let rank_card_bg = moka::future::Cache::builder()
+        .time_to_idle(std::time::Duration::from_secs(60 * 60))
+        .max_capacity(64)
         .build();
 
+    let output_cache = crate::cache::OutputCache::from_env(templates.subscribe());
+
+    let fetcher_config = crate::fetcher::FetcherConfig::from_env();
+    tracing::info!(
+        "Asset fetcher: timeout={:?}, max_bytes={}, allowed_hosts={:?}",
+        fetcher_config.timeout, fetcher_config.max_bytes, fetcher_config.allowed_hosts
+    );
+    let fetcher = crate::fetcher::AssetFetcher::new(fetcher_config)?;
+
+    let render_pool = crate::render::RenderPool::from_env();
+    tracing::info!(
+        "Render pool: {} workers, queue capacity {}",
+        render_pool.size(), render_pool.queue_capacity()
+    );
+
     let state = Arc::new(AppState {
-        fontdb: Arc::new(fontdb),
-        rank_card_bg: Arc::new(rank_card_bg),
+        text: crate::text::TextLayout::new(fonts.db()),
+        fonts,
+        rank_card_bg,
         avatar_cache,
+        disk_cache,
+        role_template: tokio::sync::OnceCell::new(),
+        default_avatars,
+        emojis,
+        color_emoji,
+        output_cache,
+        templates,
+        fetcher,
+        render_pool,
     });
 
-
-    // Initialize Prometheus recorder
-    let builder = PrometheusBuilder::new();
-    let handle = builder
-        .install_recorder()
-        .expect("failed to install Prometheus recorder");
+    // Pre-bake the default rank card background.
+    tracing::info!("Pre-baking rank card background...");
+    let bg = handler::rank_card_background(&state, &models::RankCardTheme::default(), &handler::Fallbacks::default()).await?;
+    tracing::info!("Rank card background cached ({} bytes).", bg.data().len());
 
     // Build router with middleware
     let app = Router::new()
         .route("/render", post(handler::render_rank_card))
         .route("/render/leaderboard", post(handler::render_leaderboard))
         .route("/render/role-reward/base",  post(handler::render_role_reward_base))
+        .route("/render/clan-gif", post(handler::render_clan_gif))
+        .route("/render/batch", post(handler::render_batch))
+        .route("/fonts", get(handler::list_fonts))
         .route("/metrics", get(move || {
             metrics::counter!("renderer_metrics_requests").increment(1);
             let output = handle.render();
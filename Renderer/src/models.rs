@@ -1,48 +1,432 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path};
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
 
-#[derive(Deserialize, Debug)]
+use crate::output::{AnimationFormat, OutputOptions};
+
+// ─── Validation limits ────────────────────────────────────────────────────────
+
+/// Discord caps names at 32 chars; leave room for nicknames with combining marks.
+pub const MAX_NAME_LEN: u64 = 100;
+pub const MAX_URL_LEN: u64 = 2048;
+/// The bot sends one entry per emoji code point it strips from a name, so a
+/// name made entirely of emoji (role names run to 100 chars) must still fit.
+pub const MAX_EMOJIS: u64 = MAX_NAME_LEN;
+/// LeaderboardUpdateService pages 10 users at a time — 25 leaves headroom while
+/// capping the leaderboard canvas at 800×1520.
+pub const MAX_LEADERBOARD_USERS: u64 = 25;
+/// Largest x/y/size accepted for any caller-supplied geometry.
+pub const MAX_CANVAS_DIM: u32 = 4096;
+pub const MAX_GIF_FRAMES: u64 = 600;
+pub const MAX_GIF_CLANS: u64 = 16;
+pub const MAX_BATCH_JOBS: usize = 50;
+pub const MAX_GRADIENT_STOPS: u64 = 8;
+pub const MAX_NAME_RUNS: u64 = 32;
+
+/// Accepts `#rgb`, `#rgba`, `#rrggbb` and `#rrggbbaa` — anything else could
+/// break out of the SVG attribute it is spliced into.
+fn validate_color(color: &str) -> Result<(), ValidationError> {
+    let hex = color.strip_prefix('#').unwrap_or("");
+    if matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("color").with_message("must be a hex color like #FF5500".into()))
+    }
+}
+
+/// Emoji codepoint sequences such as `1f468-200d-1f4bb`. Used as a filename,
+/// so nothing but hex digits and dashes may get through.
+fn validate_emoji_hex(hex: &str) -> Result<(), ValidationError> {
+    let valid = !hex.is_empty()
+        && hex.len() <= 64
+        && hex.split('-').all(|cp| (1..=8).contains(&cp.len()) && cp.chars().all(|c| c.is_ascii_hexdigit()));
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("emoji_hex").with_message("must be dash-separated hex codepoints".into()))
+    }
+}
+
+/// Relative path under assets/gif_templates with no `..`, root or prefix components.
+fn validate_template_path(template: &str) -> Result<(), ValidationError> {
+    let path = Path::new(template);
+    if !template.is_empty() && path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("template_path").with_message("must be a relative template directory".into()))
+    }
+}
+
+/// One entry per frame, each with no more icon positions than there can be clans.
+fn validate_gif_coords(coords: &[Vec<IconCoord>]) -> Result<(), ValidationError> {
+    if coords.iter().all(|frame| frame.len() as u64 <= MAX_GIF_CLANS) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("length").with_message(format!("each frame may list at most {} icons", MAX_GIF_CLANS).into()))
+    }
+}
+
+fn validate_animation_format(format: &str) -> Result<(), ValidationError> {
+    if AnimationFormat::from_name(format).is_some() {
+        Ok(())
+    } else {
+        Err(ValidationError::new("format").with_message("must be \"gif\" or \"webp\"".into()))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Validate)]
 pub struct RankCardRequest {
+    #[validate(length(max = MAX_NAME_LEN))]
     pub username: String,
+    #[validate(length(max = MAX_URL_LEN))]
     pub avatar_url: String,
+    /// Picks the generated default avatar when `avatar_url` is empty or fails.
+    #[validate(length(max = 32))]
+    pub user_id: Option<String>,
+    /// Legacy discriminator; a non-zero one picks the default avatar instead of `user_id`.
+    #[validate(length(max = 4))]
+    pub discriminator: Option<String>,
+    #[validate(range(min = 0))]
     pub current_xp: i32,
+    #[validate(range(min = 0))]
     pub next_xp: i32,
+    #[validate(range(min = 0))]
     pub rank: i32,
+    #[validate(range(min = 0))]
     pub level: i32,
+    #[validate(custom(function = "validate_color"))]
     pub clan_color: String,
+    /// Rich-text form of `username`; when present it is rendered instead.
+    #[validate(length(max = MAX_NAME_RUNS), nested)]
+    pub username_runs: Option<Vec<NameRun>>,
+    #[serde(default)]
+    pub normalize: NormalizeMode,
+    #[serde(default)]
+    #[validate(nested)]
+    pub theme: RankCardTheme,
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub output: OutputOptions,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Validate)]
+pub struct GradientStop {
+    /// Position along the diagonal, 0.0 (top-left) to 1.0 (bottom-right).
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub offset: f32,
+    #[validate(custom(function = "validate_color"))]
+    pub color: String,
+}
+
+/// Per-guild look of the rank card. Every field is optional in the payload;
+/// anything left out keeps the stock dark theme.
+#[derive(Deserialize, Serialize, Debug, Clone, Validate)]
+#[serde(default)]
+pub struct RankCardTheme {
+    #[validate(length(min = 1, max = MAX_GRADIENT_STOPS), nested)]
+    pub background_stops: Vec<GradientStop>,
+    /// Drawn over the gradient, cropped to fill the card.
+    #[validate(length(max = MAX_URL_LEN))]
+    pub background_image_url: Option<String>,
+    /// Username, level, rank.
+    #[validate(custom(function = "validate_color"))]
+    pub text_color: String,
+    /// "LVL", "RANK" and the XP line.
+    #[validate(custom(function = "validate_color"))]
+    pub label_color: String,
+    #[validate(custom(function = "validate_color"))]
+    pub trough_color: String,
+    #[validate(range(min = 0.0, max = 125.0))]
+    pub corner_radius: f32,
+}
+
+impl Default for RankCardTheme {
+    fn default() -> Self {
+        Self {
+            background_stops: vec![
+                GradientStop { offset: 0.0, color: "#1e1e24".to_string() },
+                GradientStop { offset: 1.0, color: "#15151a".to_string() },
+            ],
+            background_image_url: None,
+            text_color: "#ffffff".to_string(),
+            label_color: "#a0a0a0".to_string(),
+            trough_color: "#2c2c35".to_string(),
+            corner_radius: 20.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Validate)]
 pub struct EmojiData {
+    #[validate(custom(function = "validate_emoji_hex"))]
     pub hex: String,
 }
 
-#[derive(Deserialize, Debug)]
+/// One piece of a display name, in reading order. Lets the caller keep emoji
+/// where they occur in the name instead of stripping them out into `emojis`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NameRun {
+    Text { text: String },
+    /// Unicode emoji, drawn from the PNG asset for its codepoint sequence.
+    Emoji { hex: String },
+    /// Custom Discord emoji, i.e. `<:name:id>` or `<a:name:id>` when animated.
+    CustomEmoji {
+        id: String,
+        name: String,
+        #[serde(default)]
+        animated: bool,
+        /// Defaults to the emoji's Discord CDN URL.
+        url: Option<String>,
+    },
+}
+
+impl NameRun {
+    /// Image to fetch for a custom emoji. Animated emoji are drawn from their
+    /// first GIF frame.
+    pub fn custom_emoji_url(&self) -> Option<String> {
+        match self {
+            Self::CustomEmoji { url: Some(url), .. } => Some(url.clone()),
+            Self::CustomEmoji { id, animated, .. } => {
+                let ext = if *animated { "gif" } else { "png" };
+                Some(format!("https://cdn.discordapp.com/emojis/{}.{}", id, ext))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Hand-written: the derive cannot descend into an enum.
+impl Validate for NameRun {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let too_long = |max: u64| ValidationError::new("length").with_message(format!("length must be at most {}", max).into());
+        match self {
+            Self::Text { text } => {
+                if text.chars().count() as u64 > MAX_NAME_LEN {
+                    errors.add("text", too_long(MAX_NAME_LEN));
+                }
+            }
+            Self::Emoji { hex } => {
+                if let Err(e) = validate_emoji_hex(hex) {
+                    errors.add("hex", e);
+                }
+            }
+            Self::CustomEmoji { id, name, url, .. } => {
+                if id.is_empty() || id.len() > 20 || !id.chars().all(|c| c.is_ascii_digit()) {
+                    errors.add("id", ValidationError::new("snowflake").with_message("must be a Discord snowflake".into()));
+                }
+                if name.chars().count() > 32 {
+                    errors.add("name", too_long(32));
+                }
+                if url.as_ref().is_some_and(|url| url.len() as u64 > MAX_URL_LEN) {
+                    errors.add("url", too_long(MAX_URL_LEN));
+                }
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Validate)]
 pub struct LeaderboardUser {
+    #[validate(length(max = 32))]
     pub user_id: String,
+    #[validate(length(max = MAX_NAME_LEN))]
     pub username: String,
+    /// Rich-text form of `username`; when present it is rendered instead.
+    #[validate(length(max = MAX_NAME_RUNS), nested)]
+    pub username_runs: Option<Vec<NameRun>>,
+    #[validate(length(max = MAX_EMOJIS), nested)]
     pub emojis: Vec<EmojiData>,
+    #[validate(length(max = MAX_URL_LEN))]
     pub avatar_url: String,
+    /// Legacy discriminator; a non-zero one picks the default avatar instead of `user_id`.
+    #[validate(length(max = 4))]
+    pub discriminator: Option<String>,
+    #[validate(range(min = 0))]
     pub xp: i32,
+    #[validate(range(min = 0))]
     pub rank: i32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Validate)]
 pub struct LeaderboardRequest {
+    #[validate(length(max = MAX_LEADERBOARD_USERS), nested)]
     pub users: Vec<LeaderboardUser>,
     pub highlight_user_id: Option<String>,
+    #[serde(default)]
+    pub normalize: NormalizeMode,
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub output: OutputOptions,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Validate)]
 pub struct RoleRewardBaseRequest {
+    #[validate(length(max = MAX_NAME_LEN))]
     pub role_name: Option<String>,
+    #[validate(length(max = MAX_EMOJIS), nested)]
     pub emojis: Option<Vec<EmojiData>>,
+    #[validate(custom(function = "validate_color"))]
     pub role_color: String, // hex e.g. "#FF5500"
+    #[validate(length(max = MAX_URL_LEN))]
     pub icon_url: Option<String>,
+    #[validate(range(max = MAX_CANVAS_DIM))]
     pub icon_x: Option<u32>,
+    #[validate(range(max = MAX_CANVAS_DIM))]
     pub icon_y: Option<u32>,
+    #[validate(range(min = 1, max = MAX_CANVAS_DIM))]
     pub icon_size: Option<u32>,
+    #[validate(range(max = MAX_CANVAS_DIM))]
     pub text_x: Option<u32>,
+    #[validate(range(max = MAX_CANVAS_DIM))]
     pub text_y: Option<u32>,
+    #[validate(range(min = 8, max = 400))]
     pub font_size: Option<u32>,
+    /// Width of the box the emoji row + role name must fit in. Defaults to the
+    /// space between `text_x` and the right edge of the canvas.
+    #[validate(range(min = 1, max = MAX_CANVAS_DIM))]
+    pub max_text_width: Option<u32>,
+    /// How an overlong role name is made to fit; without it the name is ellipsized.
+    pub fit: Option<TextFit>,
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub output: OutputOptions,
 }
 
+/// How stylised Unicode in display names is rewritten before rendering.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizeMode {
+    /// Render names exactly as written.
+    None,
+    /// Map mathematical alphanumeric letters and digits (𝐀, 𝓐, 𝔄, 𝔸, 𝟙, …) to plain ones.
+    #[default]
+    Math,
+    /// `math` plus NFKC and a confusables table: fullwidth, circled, squared,
+    /// parenthesized, small caps and regional indicator letters.
+    Full,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextFit {
+    /// Reduce the font size (emojis included) until everything fits on one line.
+    Shrink,
+    /// Keep the font size and break the name onto a second line.
+    Wrap,
+}
+
+/// One icon position inside a single frame — the shape of each entry in coords.json.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct IconCoord {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Validate)]
+pub struct ClanGifClan {
+    #[validate(length(max = MAX_URL_LEN))]
+    pub icon_url: Option<String>,
+    /// Inclusive frame ranges `[start, end]` during which this clan's icon is drawn.
+    #[validate(length(max = MAX_GIF_FRAMES))]
+    pub visible: Vec<[u32; 2]>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Validate)]
+pub struct ClanGifRequest {
+    /// Template directory relative to assets/gif_templates, e.g. "4/storm".
+    /// Frames are read from its `frames/` subdirectory in filename order.
+    #[validate(length(max = 128), custom(function = "validate_template_path"))]
+    pub template: String,
+    /// coords[frame][clan] — identical layout to the template's coords.json.
+    #[validate(length(max = MAX_GIF_FRAMES), custom(function = "validate_gif_coords"))]
+    pub coords: Vec<Vec<IconCoord>>,
+    #[validate(length(max = MAX_GIF_CLANS), nested)]
+    pub clans: Vec<ClanGifClan>,
+    #[validate(range(min = 1, max = 512))]
+    pub icon_size: Option<u32>,
+    #[validate(range(min = 10, max = 10_000))]
+    pub frame_delay_ms: Option<u32>,
+    /// "gif" (default) or "webp" for an animated WebP.
+    #[validate(custom(function = "validate_animation_format"))]
+    pub format: Option<String>,
+}
+
+// ─── Batch Rendering ──────────────────────────────────────────────────────────
+
+/// `{"id": "...", "type": "rank_card" | "leaderboard" | "role_reward", "payload": {...}}`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum BatchJobKind {
+    RankCard(RankCardRequest),
+    Leaderboard(LeaderboardRequest),
+    RoleReward(RoleRewardBaseRequest),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchJob {
+    /// Caller-chosen key; becomes the file name inside the response archive.
+    pub id: String,
+    #[serde(flatten)]
+    pub job: BatchJobKind,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchRequest {
+    pub jobs: Vec<BatchJob>,
+}
+
+/// Job ids name files in the ZIP, so keep them to a safe filename alphabet.
+fn validate_job_id(id: &str) -> Result<(), ValidationError> {
+    let valid = (1..=64).contains(&id.len())
+        && !id.starts_with('.')
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("job_id").with_message("must be 1-64 chars of [A-Za-z0-9._-]".into()))
+    }
+}
+
+// Hand-written: the derive cannot descend into an enum or check id uniqueness.
+impl Validate for BatchRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        if self.jobs.is_empty() || self.jobs.len() > MAX_BATCH_JOBS {
+            errors.add(
+                "jobs",
+                ValidationError::new("length").with_message(format!("must contain 1 to {} jobs", MAX_BATCH_JOBS).into()),
+            );
+        }
+
+        let mut seen = HashSet::new();
+        let mut job_errors = BTreeMap::new();
+        for (index, job) in self.jobs.iter().enumerate() {
+            let mut job_err = match &job.job {
+                BatchJobKind::RankCard(p) => p.validate(),
+                BatchJobKind::Leaderboard(p) => p.validate(),
+                BatchJobKind::RoleReward(p) => p.validate(),
+            }
+            .err()
+            .unwrap_or_default();
+
+            if let Err(e) = validate_job_id(&job.id) {
+                job_err.add("id", e);
+            } else if !seen.insert(job.id.as_str()) {
+                job_err.add("id", ValidationError::new("unique").with_message("duplicate job id".into()));
+            }
+
+            if !job_err.is_empty() {
+                job_errors.insert(index, Box::new(job_err));
+            }
+        }
+        if !job_errors.is_empty() {
+            errors.errors_mut().insert("jobs", ValidationErrorsKind::List(job_errors));
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
@@ -0,0 +1,137 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::models::NormalizeMode;
+
+/// Stylised letters NFKC leaves alone, mapped to the letter they imitate.
+const CONFUSABLES: &[(char, char)] = &[
+    // Latin letter small capitals
+    ('ᴀ', 'a'), ('ʙ', 'b'), ('ᴄ', 'c'), ('ᴅ', 'd'), ('ᴇ', 'e'), ('ꜰ', 'f'), ('ɢ', 'g'),
+    ('ʜ', 'h'), ('ɪ', 'i'), ('ᴊ', 'j'), ('ᴋ', 'k'), ('ʟ', 'l'), ('ᴍ', 'm'), ('ɴ', 'n'),
+    ('ᴏ', 'o'), ('ᴘ', 'p'), ('ꞯ', 'q'), ('ʀ', 'r'), ('ꜱ', 's'), ('ᴛ', 't'), ('ᴜ', 'u'),
+    ('ᴠ', 'v'), ('ᴡ', 'w'), ('ʏ', 'y'), ('ᴢ', 'z'),
+];
+
+/// Cyrillic and Greek letters drawn identically to a Latin letter. Only applied
+/// inside words that also contain Latin letters, so real Cyrillic and Greek
+/// names are left alone while "Pаypаl" spelt with Cyrillic `а` is not.
+const LOOKALIKES: &[(char, char)] = &[
+    // Cyrillic
+    ('А', 'A'), ('В', 'B'), ('Е', 'E'), ('К', 'K'), ('М', 'M'), ('Н', 'H'), ('О', 'O'),
+    ('Р', 'P'), ('С', 'C'), ('Т', 'T'), ('Х', 'X'), ('Ѕ', 'S'), ('І', 'I'), ('Ј', 'J'),
+    ('Ԛ', 'Q'), ('Ԝ', 'W'), ('а', 'a'), ('е', 'e'), ('о', 'o'), ('р', 'p'), ('с', 'c'),
+    ('у', 'y'), ('х', 'x'), ('ѕ', 's'), ('і', 'i'), ('ј', 'j'), ('ԁ', 'd'), ('һ', 'h'),
+    ('ԛ', 'q'), ('ԝ', 'w'), ('ӏ', 'l'),
+    // Greek
+    ('Α', 'A'), ('Β', 'B'), ('Ε', 'E'), ('Ζ', 'Z'), ('Η', 'H'), ('Ι', 'I'), ('Κ', 'K'),
+    ('Μ', 'M'), ('Ν', 'N'), ('Ο', 'O'), ('Ρ', 'P'), ('Τ', 'T'), ('Υ', 'Y'), ('Χ', 'X'),
+    ('ο', 'o'), ('ν', 'v'), ('ρ', 'p'),
+];
+
+/// Applies `mode` to a display name.
+///
+/// - `none`: unchanged.
+/// - `math`: Mathematical Alphanumeric Symbols (bold, italic, script, fraktur,
+///   double-struck, sans-serif, monospace letters and digits) and the
+///   letterlike symbols that fill that block's gaps become plain letters.
+///   Everything else is left as written.
+/// - `full`: additionally maps small caps, Cyrillic and Greek lookalikes mixed
+///   into Latin words, and parenthesized, negative circled, negative squared
+///   and lone regional indicator letters, then applies NFKC, which covers
+///   fullwidth, circled and squared forms, superscripts, ligatures and the
+///   rest. Regional indicator pairs are flags and are left as written.
+pub fn normalize(text: &str, mode: NormalizeMode) -> String {
+    match mode {
+        NormalizeMode::None => text.to_string(),
+        NormalizeMode::Math => text.chars().flat_map(math_letter).collect(),
+        NormalizeMode::Full => {
+            let mut mapped = String::with_capacity(text.len());
+            for (i, word) in text.split(' ').enumerate() {
+                if i > 0 {
+                    mapped.push(' ');
+                }
+                map_confusables(word, &mut mapped);
+            }
+            mapped.nfkc().collect()
+        }
+    }
+}
+
+/// Pushes `word` to `out` with `CONFUSABLES` and stylised letters mapped, and
+/// `LOOKALIKES` too if the word has a Latin letter. Regional indicators in
+/// pairs (flags) are copied through; an unpaired one becomes its letter.
+fn map_confusables(word: &str, out: &mut String) {
+    let latin = word.chars().any(|c| c.is_ascii_alphabetic());
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_regional_indicator(c) {
+            match chars.next_if(|next| is_regional_indicator(*next)) {
+                Some(pair) => {
+                    out.push(c);
+                    out.push(pair);
+                }
+                None => out.push(confusable(c)),
+            }
+            continue;
+        }
+        let lookalike = LOOKALIKES.iter().find(|(styled, _)| *styled == c).filter(|_| latin);
+        out.push(lookalike.map_or_else(|| confusable(c), |&(_, plain)| plain));
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c as u32, 0x1F1E6..=0x1F1FF)
+}
+
+/// NFKC of `c` if it is a mathematical alphanumeric, or a letterlike symbol
+/// that decomposes to a single ASCII letter (ℝ, ℊ, ℌ, …); `c` otherwise.
+fn math_letter(c: char) -> Vec<char> {
+    let decomposed = || std::iter::once(c).nfkc().collect::<Vec<char>>();
+    match c as u32 {
+        0x1D400..=0x1D7FF => decomposed(),
+        0x2100..=0x214F => match decomposed().as_slice() {
+            [letter] if letter.is_ascii_alphabetic() => vec![*letter],
+            _ => vec![c],
+        },
+        _ => vec![c],
+    }
+}
+
+fn confusable(c: char) -> char {
+    if let Some(&(_, plain)) = CONFUSABLES.iter().find(|(styled, _)| *styled == c) {
+        return plain;
+    }
+    let letter = |base: u32, first: char| char::from_u32(c as u32 - base + first as u32).unwrap_or(c);
+    match c as u32 {
+        // Parenthesized ⒜–⒵ (NFKC would keep the parentheses)
+        0x249C..=0x24B5 => letter(0x249C, 'a'),
+        // Negative circled 🅐–🅩 and negative squared 🅰–🆉
+        0x1F150..=0x1F169 => letter(0x1F150, 'A'),
+        0x1F170..=0x1F189 => letter(0x1F170, 'A'),
+        // Unpaired regional indicators 🇦–🇿
+        0x1F1E6..=0x1F1FF => letter(0x1F1E6, 'A'),
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_keeps_flags_and_maps_a_lone_regional_indicator() {
+        assert_eq!(normalize("GG 🇯🇵🇫🇷", NormalizeMode::Full), "GG 🇯🇵🇫🇷");
+        assert_eq!(normalize("🇯🇵🇫", NormalizeMode::Full), "🇯🇵F");
+    }
+
+    #[test]
+    fn full_maps_lookalikes_only_inside_latin_words() {
+        // Cyrillic а and о in an otherwise Latin name.
+        assert_eq!(normalize("Pаypаl Gоku", NormalizeMode::Full), "Paypal Goku");
+        assert_eq!(normalize("Миша Αθήνα", NormalizeMode::Full), "Миша Αθήνα");
+    }
+
+    #[test]
+    fn full_maps_fullwidth_and_small_caps() {
+        assert_eq!(normalize("ＧＯＫＵ ɢᴏᴋᴜ", NormalizeMode::Full), "GOKU goku");
+    }
+}
@@ -0,0 +1,356 @@
+use axum::http::{header, HeaderMap};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::error::{EncodingError, ImageFormatHint};
+use image::{ExtendedColorType, ImageEncoder, ImageError, ImageFormat, ImageResult};
+use serde::{Deserialize, Serialize};
+use tiny_skia::Pixmap;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+/// Default quality for the lossy encoders (JPEG, AVIF) when the caller gives none.
+const DEFAULT_QUALITY: u8 = 85;
+
+/// AVIF encoder speed (1 = slowest/best, 10 = fastest). 8 keeps a 3041×894
+/// role reward image well under a second.
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    WebP,
+    Avif,
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "png" => Some(Self::Png),
+            "webp" => Some(Self::WebP),
+            "avif" => Some(Self::Avif),
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            _ => None,
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime.trim().to_ascii_lowercase().as_str() {
+            "image/png" | "image/*" | "*/*" => Some(Self::Png),
+            "image/webp" => Some(Self::WebP),
+            "image/avif" => Some(Self::Avif),
+            "image/jpeg" | "image/jpg" => Some(Self::Jpeg),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+            Self::Jpeg => "jpg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+            Self::Avif => "image/avif",
+            Self::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// Container for animated output (the clan GIF route).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Gif,
+    WebP,
+}
+
+impl AnimationFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Gif => "image/gif",
+            Self::WebP => "image/webp",
+        }
+    }
+}
+
+/// Output options shared by every render request (flattened into the payload).
+///   - `format`: "png" | "webp" | "avif" | "jpeg" — overrides the Accept header
+///   - `quality`: 1–100, used by JPEG and AVIF. WebP is always lossless, so
+///     `quality` is rejected with `format: "webp"` and ignored when WebP is
+///     picked from the Accept header.
+///   - `background`: hex colour JPEG output is flattened onto, since it has no
+///     alpha channel (default white).
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct OutputOptions {
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+    pub background: Option<String>,
+}
+
+// Hand-written: whether `quality` is accepted depends on `format`.
+impl Validate for OutputOptions {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let format = self.format.as_deref().map(OutputFormat::from_name);
+        if let Some(None) = format {
+            errors.add("format", ValidationError::new("format").with_message("must be one of png, webp, avif, jpeg".into()));
+        }
+        match self.quality {
+            Some(quality) if !(1..=100).contains(&quality) => {
+                errors.add("quality", ValidationError::new("range").with_message("must be between 1 and 100".into()));
+            }
+            Some(_) if format == Some(Some(OutputFormat::WebP)) => {
+                errors.add("quality", ValidationError::new("quality").with_message("WebP output is lossless and takes no quality".into()));
+            }
+            _ => {}
+        }
+        if self.background.as_deref().is_some_and(|color| parse_color(color).is_none()) {
+            errors.add("background", ValidationError::new("color").with_message("must be a hex color like #FFFFFF".into()));
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+impl OutputOptions {
+    /// Picks the output format: explicit `format` field first, then the highest-q
+    /// supported type in `Accept`, then PNG. Returns `Err` with the bad value if
+    /// `format` names something we cannot encode.
+    pub fn resolve(&self, headers: &HeaderMap) -> Result<OutputFormat, String> {
+        if let Some(name) = &self.format {
+            return OutputFormat::from_name(name).ok_or_else(|| name.clone());
+        }
+
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+
+        let mut best: Option<(OutputFormat, f32)> = None;
+        for entry in accept.split(',') {
+            let mut parts = entry.split(';');
+            let Some(format) = parts.next().and_then(OutputFormat::from_mime) else { continue };
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((format, q));
+            }
+        }
+
+        Ok(best.map(|(format, _)| format).unwrap_or(OutputFormat::Png))
+    }
+}
+
+/// Encodes a rendered Pixmap in the format and with the options requested.
+pub fn encode(pixmap: &Pixmap, format: OutputFormat, options: &OutputOptions) -> ImageResult<Vec<u8>> {
+    let quality = options.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100);
+    let (width, height) = (pixmap.width(), pixmap.height());
+
+    // tiny-skia stores premultiplied RGBA; every encoder below wants straight alpha.
+    let rgba = || -> Vec<u8> {
+        pixmap
+            .pixels()
+            .iter()
+            .flat_map(|p| {
+                let c = p.demultiply();
+                [c.red(), c.green(), c.blue(), c.alpha()]
+            })
+            .collect()
+    };
+
+    let mut out = Vec::new();
+    match format {
+        OutputFormat::Png => {
+            PngEncoder::new(&mut out).write_image(&rgba(), width, height, ExtendedColorType::Rgba8)?;
+        }
+        OutputFormat::WebP => {
+            WebPEncoder::new_lossless(&mut out).write_image(&rgba(), width, height, ExtendedColorType::Rgba8)?;
+        }
+        OutputFormat::Avif => {
+            AvifEncoder::new_with_speed_quality(&mut out, AVIF_SPEED, quality)
+                .write_image(&rgba(), width, height, ExtendedColorType::Rgba8)?;
+        }
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel: composite onto the background so
+            // transparent corners come out in that colour, not black.
+            let [r, g, b, _] = options.background.as_deref().and_then(parse_color).unwrap_or([255; 4]);
+            let rgb: Vec<u8> = pixmap
+                .pixels()
+                .iter()
+                .flat_map(|p| {
+                    let under = 255 - p.alpha() as u32;
+                    let over = |premultiplied: u8, bg: u8| (premultiplied as u32 + (bg as u32 * under + 127) / 255) as u8;
+                    [over(p.red(), r), over(p.green(), g), over(p.blue(), b)]
+                })
+                .collect();
+            JpegEncoder::new_with_quality(&mut out, quality)
+                .write_image(&rgb, width, height, ExtendedColorType::Rgb8)?;
+        }
+    }
+    Ok(out)
+}
+
+/// `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa` as RGBA bytes.
+fn parse_color(color: &str) -> Option<[u8; 4]> {
+    let hex = color.strip_prefix('#')?;
+    let digits: Vec<u8> = hex.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect::<Option<_>>()?;
+    let channels: Vec<u8> = match digits.len() {
+        3 | 4 => digits.iter().map(|d| d * 17).collect(),
+        6 | 8 => digits.chunks(2).map(|pair| pair[0] * 16 + pair[1]).collect(),
+        _ => return None,
+    };
+    Some([channels[0], channels[1], channels[2], channels.get(3).copied().unwrap_or(255)])
+}
+
+/// Builds an animated WebP from lossless frames. Each frame is encoded as a
+/// still WebP and its `VP8L` bitstream wrapped in an `ANMF` chunk, after a
+/// `VP8X` header and an `ANIM` chunk that loops forever, following the WebP
+/// container spec. Frames cover the whole canvas and replace the previous one.
+pub struct AnimatedWebP {
+    width: u32,
+    height: u32,
+    frames: Vec<u8>,
+}
+
+impl AnimatedWebP {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, frames: Vec::new() }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Appends a `width`×`height` straight-alpha RGBA frame shown for `delay_ms`.
+    pub fn add_frame(&mut self, rgba: &[u8], delay_ms: u32) -> ImageResult<()> {
+        let mut still = Vec::new();
+        WebPEncoder::new_lossless(&mut still).write_image(rgba, self.width, self.height, ExtendedColorType::Rgba8)?;
+        let bitstream = find_chunk(&still, b"VP8L").ok_or_else(|| {
+            ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::WebP), "encoder produced no VP8L chunk"))
+        })?;
+
+        let mut frame = Vec::with_capacity(24 + bitstream.len());
+        frame.extend_from_slice(&u24(0)); // x / 2
+        frame.extend_from_slice(&u24(0)); // y / 2
+        frame.extend_from_slice(&u24(self.width - 1));
+        frame.extend_from_slice(&u24(self.height - 1));
+        frame.extend_from_slice(&u24(delay_ms));
+        frame.push(0b10); // no blending, no disposal
+        push_chunk(&mut frame, b"VP8L", bitstream);
+        push_chunk(&mut self.frames, b"ANMF", &frame);
+        Ok(())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut vp8x = vec![0b0001_0010, 0, 0, 0]; // alpha + animation
+        vp8x.extend_from_slice(&u24(self.width - 1));
+        vp8x.extend_from_slice(&u24(self.height - 1));
+        // Transparent background, loop count 0 (forever).
+        let anim = [0u8; 6];
+
+        let mut body = b"WEBP".to_vec();
+        push_chunk(&mut body, b"VP8X", &vp8x);
+        push_chunk(&mut body, b"ANIM", &anim);
+        body.extend_from_slice(&self.frames);
+
+        let mut out = Vec::with_capacity(8 + body.len());
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(&body);
+        out
+    }
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.min(0xFF_FFFF).to_le_bytes();
+    [a, b, c]
+}
+
+/// RIFF chunk: fourcc, little-endian size, data, padded to an even length.
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Data of the first `fourcc` chunk in a RIFF WebP file.
+fn find_chunk<'a>(file: &'a [u8], fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut rest = file.get(12..)?;
+    while rest.len() >= 8 {
+        let size = u32::from_le_bytes(rest[4..8].try_into().ok()?) as usize;
+        let data = rest.get(8..8 + size)?;
+        if &rest[..4] == fourcc {
+            return Some(data);
+        }
+        rest = rest.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::webp::WebPDecoder;
+    use image::AnimationDecoder;
+
+    #[test]
+    fn animated_webp_decodes_with_every_frame() {
+        let mut webp = AnimatedWebP::new(3, 2);
+        let red = [255, 0, 0, 255].repeat(6);
+        let clear = [0, 0, 255, 0].repeat(6);
+        webp.add_frame(&red, 100).unwrap();
+        webp.add_frame(&clear, 250).unwrap();
+        let bytes = webp.finish();
+
+        let decoder = WebPDecoder::new(std::io::Cursor::new(bytes)).unwrap();
+        assert!(decoder.has_animation());
+        let frames = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().dimensions(), (3, 2));
+        assert_eq!(frames[0].buffer().get_pixel(2, 1).0, [255, 0, 0, 255]);
+        assert_eq!(frames[1].buffer().get_pixel(0, 0).0[3], 0);
+        assert_eq!(frames[1].delay().numer_denom_ms(), (250, 1));
+    }
+
+    #[test]
+    fn jpeg_flattens_transparency_onto_background() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        let options = OutputOptions { background: Some("#0f0".into()), ..Default::default() };
+        let bytes = encode(&pixmap, OutputFormat::Jpeg, &options).unwrap();
+        let decoded = image::load_from_memory(&bytes).unwrap().to_rgb8();
+        let [r, g, b] = decoded.get_pixel(1, 1).0;
+        assert!(r < 8 && g > 247 && b < 8, "got {r},{g},{b}");
+    }
+
+    #[test]
+    fn quality_is_rejected_for_explicit_webp() {
+        let options = OutputOptions { format: Some("webp".into()), quality: Some(80), ..Default::default() };
+        assert!(options.validate().unwrap_err().field_errors().contains_key("quality"));
+        assert!(OutputOptions { quality: Some(80), ..Default::default() }.validate().is_ok());
+        assert_eq!(parse_color("#11223380"), Some([0x11, 0x22, 0x33, 0x80]));
+        assert_eq!(parse_color("112233"), None);
+    }
+}
@@ -0,0 +1,342 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tiny_skia::{FilterQuality, Paint, Path, PathBuilder, Pattern, Pixmap, Rect, SpreadMode, Transform};
+use tokio::sync::Semaphore;
+use usvg::fontdb::Database;
+use usvg::{Options, Tree, TreeParsing, TreePostProc};
+
+use crate::error::RenderError;
+use crate::output::{self, OutputFormat, OutputOptions};
+
+/// A fully laid-out render: SVG markup for the vector and text content, the
+/// raster layers drawn over it, and the canvas both go onto. Handlers build
+/// these asynchronously (fetches, text measurement, templating); `rasterize` is
+/// the purely CPU-bound remainder.
+pub struct RenderJob {
+    pub svg: String,
+    pub font_family: String,
+    /// Blank canvas, or a clone of a pre-baked background to draw on top of.
+    pub canvas: Pixmap,
+    /// Avatars, icons and emoji, composited after the SVG in order.
+    pub layers: Vec<RasterLayer>,
+    pub format: OutputFormat,
+    pub output: OutputOptions,
+}
+
+impl RenderJob {
+    /// Parses the SVG, converts text to paths, draws onto the canvas, composites
+    /// the raster layers and encodes.
+    pub fn rasterize(self, fontdb: &Database) -> Result<Vec<u8>, RenderError> {
+        let mut pixmap = draw(&self.svg, &self.font_family, self.canvas, fontdb)?;
+        composite(&mut pixmap, &self.layers);
+        Ok(output::encode(&pixmap, self.format, &self.output)?)
+    }
+}
+
+/// How an image is scaled into its layer's box.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Fit {
+    /// Whole image visible, centred: SVG's default `xMidYMid meet`.
+    Contain,
+    /// Box fully covered, centred, overflow cut: `xMidYMid slice`.
+    Cover,
+}
+
+/// The box a remote image is drawn into, which is also the size it is decoded
+/// and cached at.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ImageBox {
+    pub width: u32,
+    pub height: u32,
+    pub fit: Fit,
+}
+
+impl ImageBox {
+    pub const fn contain(width: u32, height: u32) -> Self {
+        Self { width, height, fit: Fit::Contain }
+    }
+
+    pub const fn cover(width: u32, height: u32) -> Self {
+        Self { width, height, fit: Fit::Cover }
+    }
+
+    /// Size an `image_width`×`image_height` image is drawn at in this box. Never
+    /// larger than the image itself: small images are enlarged when drawn instead.
+    fn scaled(&self, image_width: u32, image_height: u32) -> (u32, u32) {
+        let scale_x = self.width as f32 / image_width as f32;
+        let scale_y = self.height as f32 / image_height as f32;
+        let scale = match self.fit {
+            Fit::Contain => scale_x.min(scale_y),
+            Fit::Cover => scale_x.max(scale_y),
+        };
+        if scale >= 1.0 {
+            return (image_width, image_height);
+        }
+        let size = |side: u32| ((side as f32 * scale).round() as u32).max(1);
+        (size(image_width), size(image_height))
+    }
+}
+
+/// Shape a layer is cut to, within the part of its box the image covers.
+#[derive(Clone, Copy)]
+pub enum Clip {
+    Rect,
+    RoundedRect(f32),
+    /// The ellipse inscribed in the box; a circle for square boxes.
+    Circle,
+}
+
+/// A decoded image drawn straight onto the canvas, instead of travelling through
+/// the SVG as a base64 data URI that usvg would decode again on every render.
+/// Layers go on after the SVG, so templates leave their area free of vector
+/// content that should sit on top.
+pub struct RasterLayer {
+    image: Arc<Pixmap>,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    fit: Fit,
+    clip: Clip,
+}
+
+impl RasterLayer {
+    /// `image` fitted inside the box at (`x`, `y`), unclipped.
+    pub fn new(image: Arc<Pixmap>, x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self { image, x, y, width, height, fit: Fit::Contain, clip: Clip::Rect }
+    }
+
+    pub fn fit(mut self, fit: Fit) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    pub fn clip(mut self, clip: Clip) -> Self {
+        self.clip = clip;
+        self
+    }
+
+    /// Fills the clip shape with the image as a bicubic pattern, the filter usvg
+    /// uses for `<image>` at its default `image-rendering`.
+    fn draw(&self, canvas: &mut Pixmap) {
+        let (image_width, image_height) = (self.image.width() as f32, self.image.height() as f32);
+        let (scale_x, scale_y) = (self.width / image_width, self.height / image_height);
+        let scale = match self.fit {
+            Fit::Contain => scale_x.min(scale_y),
+            Fit::Cover => scale_x.max(scale_y),
+        };
+        let (width, height) = (image_width * scale, image_height * scale);
+        let left = self.x + (self.width - width) / 2.0;
+        let top = self.y + (self.height - height) / 2.0;
+
+        // Only the part of the box the image actually covers is filled, so a
+        // contained image is never padded out with its edge pixels.
+        let visible = Rect::from_ltrb(
+            left.max(self.x),
+            top.max(self.y),
+            (left + width).min(self.x + self.width),
+            (top + height).min(self.y + self.height),
+        );
+        let Some(path) = visible.and_then(|rect| clip_path(rect, self.clip)) else { return };
+
+        let paint = Paint {
+            shader: Pattern::new(
+                Pixmap::as_ref(&self.image),
+                SpreadMode::Pad,
+                FilterQuality::Bicubic,
+                1.0,
+                Transform::from_row(scale, 0.0, 0.0, scale, left, top),
+            ),
+            anti_alias: true,
+            ..Paint::default()
+        };
+        canvas.fill_path(&path, &paint, tiny_skia::FillRule::Winding, Transform::identity(), None);
+    }
+}
+
+fn clip_path(rect: Rect, clip: Clip) -> Option<Path> {
+    match clip {
+        Clip::Rect => Some(PathBuilder::from_rect(rect)),
+        Clip::Circle => PathBuilder::from_oval(rect),
+        Clip::RoundedRect(radius) => rounded_rect(rect, radius),
+    }
+}
+
+/// `rect` with corners of `radius` (as SVG `rx`, clamped to half the shorter side).
+fn rounded_rect(rect: Rect, radius: f32) -> Option<Path> {
+    let radius = radius.min(rect.width() / 2.0).min(rect.height() / 2.0);
+    if radius <= 0.0 {
+        return Some(PathBuilder::from_rect(rect));
+    }
+    // Control point inset for a cubic quarter circle.
+    let k = radius * (1.0 - 0.552_284_8);
+    let (l, t, r, b) = (rect.left(), rect.top(), rect.right(), rect.bottom());
+    let mut pb = PathBuilder::new();
+    pb.move_to(l + radius, t);
+    pb.line_to(r - radius, t);
+    pb.cubic_to(r - k, t, r, t + k, r, t + radius);
+    pb.line_to(r, b - radius);
+    pb.cubic_to(r, b - k, r - k, b, r - radius, b);
+    pb.line_to(l + radius, b);
+    pb.cubic_to(l + k, b, l, b - k, l, b - radius);
+    pb.line_to(l, t + radius);
+    pb.cubic_to(l, t + k, l + k, t, l + radius, t);
+    pb.close();
+    pb.finish()
+}
+
+/// Draws `layers` onto `canvas` in order.
+pub fn composite(canvas: &mut Pixmap, layers: &[RasterLayer]) {
+    for layer in layers {
+        layer.draw(canvas);
+    }
+}
+
+/// Decodes any image format the `image` crate reads into a premultiplied Pixmap.
+pub fn decode_image(bytes: &[u8]) -> Option<Pixmap> {
+    let img = image::load_from_memory(bytes).ok()?;
+    rgba_to_pixmap(&img.to_rgba8())
+}
+
+/// Like `decode_image`, then downscales (Lanczos3) to the size the image is
+/// drawn at in `target`, so its layer composites without resampling.
+pub fn decode_image_for(bytes: &[u8], target: ImageBox) -> Option<Pixmap> {
+    let img = image::load_from_memory(bytes).ok()?.to_rgba8();
+    let (width, height) = target.scaled(img.width(), img.height());
+    if (width, height) == img.dimensions() {
+        return rgba_to_pixmap(&img);
+    }
+    rgba_to_pixmap(&image::imageops::resize(&img, width, height, image::imageops::FilterType::Lanczos3))
+}
+
+/// Converts a straight-alpha RGBA image into a premultiplied tiny-skia Pixmap.
+pub fn rgba_to_pixmap(img: &image::RgbaImage) -> Option<Pixmap> {
+    let mut pixmap = Pixmap::new(img.width(), img.height())?;
+    for (dst, src) in pixmap.pixels_mut().iter_mut().zip(img.pixels()) {
+        let [r, g, b, a] = src.0;
+        *dst = tiny_skia::ColorU8::from_rgba(r, g, b, a).premultiply();
+    }
+    Some(pixmap)
+}
+
+/// Converts a premultiplied Pixmap back into straight-alpha RGBA for the `image` encoders.
+pub fn pixmap_to_rgba(pixmap: &Pixmap) -> image::RgbaImage {
+    let mut img = image::RgbaImage::new(pixmap.width(), pixmap.height());
+    for (dst, src) in img.pixels_mut().zip(pixmap.pixels()) {
+        let c = src.demultiply();
+        *dst = image::Rgba([c.red(), c.green(), c.blue(), c.alpha()]);
+    }
+    img
+}
+
+/// Parses `svg`, converts text to paths and draws it onto `canvas`.
+pub fn draw(svg: &str, font_family: &str, mut canvas: Pixmap, fontdb: &Database) -> Result<Pixmap, RenderError> {
+    let opt = Options {
+        font_family: font_family.to_string(),
+        ..Options::default()
+    };
+
+    let mut rtree = Tree::from_str(svg, &opt)?;
+    rtree.postprocess(usvg::PostProcessingSteps::default(), fontdb);
+    resvg::render(&rtree, usvg::Transform::default(), &mut canvas.as_mut());
+
+    Ok(canvas)
+}
+
+/// Bounded pool for all CPU-bound rendering work.
+///
+/// At most `size` tasks run at once on tokio's blocking threads; up to
+/// `queue_capacity` more may wait for a slot. Anything beyond that is rejected
+/// with `RenderError::Busy` (503 + Retry-After) instead of piling up latency
+/// behind a backlog of 3041×894 role rewards.
+pub struct RenderPool {
+    slots: Arc<Semaphore>,
+    size: usize,
+    queue_capacity: usize,
+    waiting: AtomicUsize,
+}
+
+impl RenderPool {
+    pub fn new(size: usize, queue_capacity: usize) -> Self {
+        let size = size.max(1);
+        metrics::gauge!("renderer_render_pool_size").set(size as f64);
+        Self {
+            slots: Arc::new(Semaphore::new(size)),
+            size,
+            queue_capacity,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// `RENDER_POOL_SIZE` (default: available cores) and `RENDER_QUEUE_CAPACITY` (default 64).
+    pub fn from_env() -> Self {
+        let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+        let size = std::env::var("RENDER_POOL_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(cores);
+        let queue_capacity = std::env::var("RENDER_QUEUE_CAPACITY").ok().and_then(|v| v.parse().ok()).unwrap_or(64);
+        Self::new(size, queue_capacity)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity
+    }
+
+    /// Runs `task` on the pool, waiting for a free slot if needed.
+    pub async fn run<T, F>(&self, task: F) -> Result<T, RenderError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        // Admission: every caller waiting for a slot counts against the queue.
+        let queued = QueueTicket::take(&self.waiting);
+        if queued.depth > self.queue_capacity && self.slots.available_permits() == 0 {
+            metrics::counter!("renderer_render_queue_rejected_total").increment(1);
+            return Err(RenderError::Busy { retry_after_secs: 1 });
+        }
+
+        let wait_start = Instant::now();
+        let permit = self.slots.clone().acquire_owned().await;
+        drop(queued);
+        metrics::histogram!("renderer_render_queue_wait_seconds").record(wait_start.elapsed().as_secs_f64());
+
+        let permit = permit.map_err(|e| RenderError::Worker(e.to_string()))?;
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            task()
+        })
+        .await
+        .map_err(|e| RenderError::Worker(e.to_string()))
+    }
+
+    /// Rasterizes and encodes `job` on the pool.
+    pub async fn rasterize(&self, job: RenderJob, fontdb: Arc<Database>) -> Result<Vec<u8>, RenderError> {
+        self.run(move || job.rasterize(&fontdb)).await?
+    }
+}
+
+/// Holds one place in the render queue; released on drop so a request cancelled
+/// while waiting (client hung up) does not leak queue depth.
+struct QueueTicket<'a> {
+    waiting: &'a AtomicUsize,
+    /// Queue depth including this ticket.
+    depth: usize,
+}
+
+impl<'a> QueueTicket<'a> {
+    fn take(waiting: &'a AtomicUsize) -> Self {
+        let depth = waiting.fetch_add(1, Ordering::SeqCst) + 1;
+        metrics::gauge!("renderer_render_queue_depth").set(depth as f64);
+        Self { waiting, depth }
+    }
+}
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        let depth = self.waiting.fetch_sub(1, Ordering::SeqCst) - 1;
+        metrics::gauge!("renderer_render_queue_depth").set(depth as f64);
+    }
+}
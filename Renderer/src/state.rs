@@ -1,10 +1,36 @@
 use std::sync::Arc;
 use moka::future::Cache;
 use tiny_skia::Pixmap;
-use usvg::fontdb::Database;
+use tokio::sync::OnceCell;
+
+use crate::cache::{AvatarCache, OutputCache};
+use crate::color_emoji::ColorEmojiFont;
+use crate::default_avatar::DefaultAvatars;
+use crate::disk_cache::DiskCache;
+use crate::emoji::EmojiStore;
+use crate::fetcher::AssetFetcher;
+use crate::fonts::FontRegistry;
+use crate::render::RenderPool;
+use crate::template_engine::TemplateEngine;
+use crate::text::TextLayout;
 
 pub struct AppState {
-    pub fontdb: Arc<Database>,
-    pub rank_card_bg: Arc<Pixmap>,
-    pub avatar_cache: Cache<String, String>,
+    pub fonts: FontRegistry,
+    pub text: TextLayout,
+    /// Pre-baked rank card backgrounds, keyed by template version + theme hash.
+    pub rank_card_bg: Cache<String, Arc<Pixmap>>,
+    pub avatar_cache: AvatarCache,
+    /// Fetched image bodies kept across restarts; `None` unless `DISK_CACHE_DIR` is set.
+    pub disk_cache: Option<DiskCache>,
+    /// Decoded role reward template, the canvas every role reward starts from.
+    pub role_template: OnceCell<Arc<Pixmap>>,
+    /// Generated avatars drawn for a missing or failed avatar URL.
+    pub default_avatars: DefaultAvatars,
+    pub emojis: EmojiStore,
+    /// Draws emoji typed directly into names; `None` without a colour emoji font.
+    pub color_emoji: Option<ColorEmojiFont>,
+    pub output_cache: OutputCache,
+    pub templates: TemplateEngine,
+    pub fetcher: AssetFetcher,
+    pub render_pool: RenderPool,
 }
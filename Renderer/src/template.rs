@@ -1,36 +1,109 @@
 use askama::Template;
+use serde::Serialize;
 
-/// Zero-field template — renders only the static gradient background + progress trough.
-/// Pre-rendered once at startup; cloned per request.
-#[derive(Template)]
+/// A context struct that can be rendered either by its compiled-in Askama
+/// template or by a runtime-loaded copy of the same file (see `template_engine`).
+pub trait SvgTemplate: Template + Serialize {
+    /// File name under `templates/` (and under `TEMPLATE_DIR` in runtime mode).
+    const FILE: &'static str;
+    /// Compiled-in source, hashed into the template version.
+    const SOURCE: &'static str;
+    /// Representative context a runtime copy must render cleanly before it is used.
+    fn sample() -> Self;
+}
+
+macro_rules! svg_template {
+    ($ty:ty, $file:literal, $sample:expr) => {
+        impl SvgTemplate for $ty {
+            const FILE: &'static str = $file;
+            const SOURCE: &'static str = include_str!(concat!("../templates/", $file));
+            fn sample() -> Self {
+                $sample
+            }
+        }
+    };
+}
+
+#[derive(Serialize)]
+pub struct TemplateGradientStop {
+    pub offset: f32,
+    pub color: String,
+}
+
+/// Themed gradient background; the optional background image is composited
+/// over it. Pre-rendered once per distinct theme; cloned per request.
+#[derive(Template, Serialize)]
 #[template(path = "rank_card_bg.svg", escape = "xml")]
-pub struct RankCardBackgroundTemplate {}
+pub struct RankCardBackgroundTemplate {
+    pub stops: Vec<TemplateGradientStop>,
+    pub corner_radius: f32,
+}
+
+svg_template!(RankCardBackgroundTemplate, "rank_card_bg.svg", RankCardBackgroundTemplate {
+    stops: vec![
+        TemplateGradientStop { offset: 0.0, color: "#1e1e24".to_string() },
+        TemplateGradientStop { offset: 1.0, color: "#15151a".to_string() },
+    ],
+    corner_radius: 20.0,
+});
+
+/// One positioned text span of a display name, in `font`. Inline emoji are
+/// composited as raster layers instead.
+#[derive(Serialize)]
+pub struct TemplateNameRun {
+    pub text: String,
+    pub font: String,
+    pub x: f64,
+}
 
-#[derive(Template)]
+#[derive(Template, Serialize)]
 #[template(path = "rank_card.svg", escape = "xml")]
 pub struct RankCardTemplate {
-    pub username: String,
-    pub avatar_b64: String,
+    pub name_runs: Vec<TemplateNameRun>,
     pub current_xp: i32,
     pub next_xp: i32,
     pub rank: i32,
     pub level: i32,
     pub clan_color: String,
     pub progress_width: f64,
-    pub use_system_font: bool,
+    pub trough_color: String,
+    pub username_font: String,
+    /// `font-family` of the LVL/RANK and XP lines.
+    pub label_font: String,
+    pub text_color: String,
+    pub label_color: String,
 }
 
-pub struct TemplateEmojiData {
-    pub b64: String,
-    pub x_offset: f64,
+svg_template!(RankCardTemplate, "rank_card.svg", RankCardTemplate {
+    name_runs: vec![
+        TemplateNameRun { text: "sample".to_string(), font: "Poppins, sans-serif".to_string(), x: 250.0 },
+    ],
+    current_xp: 50,
+    next_xp: 100,
+    rank: 1,
+    level: 1,
+    clan_color: "#ffffff".to_string(),
+    progress_width: 250.0,
+    trough_color: "#2c2c35".to_string(),
+    username_font: "Poppins, sans-serif".to_string(),
+    label_font: "Poppins, sans-serif".to_string(),
+    text_color: "#ffffff".to_string(),
+    label_color: "#a0a0a0".to_string(),
+});
+
+/// One line of a multi-line `<text>`, positioned absolutely.
+#[derive(Serialize)]
+pub struct TemplateTextLine {
+    pub text: String,
+    pub x: f64,
+    pub y: f64,
 }
 
+#[derive(Serialize)]
 pub struct TemplateUserData {
-    pub username: String,
-    pub avatar_b64: String,
+    pub name_runs: Vec<TemplateNameRun>,
     pub rank: i32,
     pub formatted_xp: String,
-    pub emojis: Vec<TemplateEmojiData>,
     pub rank_x_start: f64,
     pub separator_x_start: f64,
     pub username_x_start: f64,
@@ -38,42 +111,64 @@ pub struct TemplateUserData {
     pub bg_color: String,
     pub y_pos: i32,
     pub xp_x_start: f64,
-    pub use_system_font: bool,
+    pub username_font: String,
 }
 
-#[derive(Template)]
+#[derive(Template, Serialize)]
 #[template(path = "leaderboard.svg", escape = "xml")]
 pub struct LeaderboardTemplate {
     pub users: Vec<TemplateUserData>,
     pub height: i32,
+    /// `font-family` of rank, separators and XP text.
+    pub label_font: String,
 }
 
+svg_template!(LeaderboardTemplate, "leaderboard.svg", LeaderboardTemplate {
+    users: vec![TemplateUserData {
+        name_runs: vec![
+            TemplateNameRun { text: "sample".to_string(), font: "Poppins, sans-serif".to_string(), x: 150.0 },
+        ],
+        rank: 1,
+        formatted_xp: "1,000".to_string(),
+        rank_x_start: 80.0,
+        separator_x_start: 130.0,
+        username_x_start: 150.0,
+        separator2_x_start: 400.0,
+        bg_color: "#1f1f1f".to_string(),
+        y_pos: 0,
+        xp_x_start: 420.0,
+        username_font: "Poppins, sans-serif".to_string(),
+    }],
+    height: 57,
+    label_font: "Poppins, sans-serif".to_string(),
+});
+
 // ─── Role Reward Templates ────────────────────────────────────────────────────
 
-/// Rendered as an SVG that embeds the template PNG + icon + role name text.
-/// All coordinates match the original Node.js canvas geometry:
-///   - Template size: 3041 × 894
-///   - Icon:  x=74, y=67, size=171×172, clipped to circle (cx=159, cy=153, r=85)
-///   - Role name: x=298, y=159 (baseline), font-size=50
-#[derive(Template)]
+/// Role name text, drawn onto a copy of the template PNG (3041 × 894); the
+/// role icon and emoji row are composited over it as raster layers.
+/// The role name is one line, or two when the request asks for `fit: wrap`.
+#[derive(Template, Serialize)]
 #[template(path = "role_reward_base.svg", escape = "xml")]
 pub struct RoleRewardBaseTemplate {
-    pub template_b64: String,
-    pub icon_b64: String,
-    pub role_name: String,
-    pub emojis: Vec<TemplateEmojiData>,
+    pub role_lines: Vec<TemplateTextLine>,
     pub role_color: String,
+    pub role_font: String,
     // canvas
     pub canvas_width: u32,
     pub canvas_height: u32,
-    // icon geometry
-    pub icon_x: u32,
-    pub icon_y: u32,
-    pub icon_size: u32,
     // text geometry
-    pub text_x: u32,
-    pub text_y: u32,
     pub font_size: u32,
-    pub emoji_y: f64,
 }
 
+svg_template!(RoleRewardBaseTemplate, "role_reward_base.svg", RoleRewardBaseTemplate {
+    role_lines: vec![
+        TemplateTextLine { text: "sample".to_string(), x: 298.0, y: 130.0 },
+        TemplateTextLine { text: "role".to_string(), x: 298.0, y: 190.0 },
+    ],
+    role_color: "#ffffff".to_string(),
+    role_font: "Poppins, sans-serif".to_string(),
+    canvas_width: 3041,
+    canvas_height: 894,
+    font_size: 50,
+});
@@ -0,0 +1,228 @@
+use minijinja::{AutoEscape, Environment, Error, ErrorKind, UndefinedBehavior, Value};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use usvg::TreeParsing;
+
+use crate::cache;
+use crate::error::RenderError;
+use crate::template::{
+    LeaderboardTemplate, RankCardBackgroundTemplate, RankCardTemplate, RoleRewardBaseTemplate, SvgTemplate,
+};
+
+/// How long to wait for an editor's burst of write/rename events to settle.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// One template the engine can override at runtime.
+struct KnownTemplate {
+    file: &'static str,
+    compiled: &'static str,
+    validate: fn(&Environment<'static>) -> Result<(), String>,
+}
+
+fn known<T: SvgTemplate>() -> KnownTemplate {
+    KnownTemplate {
+        file: T::FILE,
+        compiled: T::SOURCE,
+        validate: validate::<T>,
+    }
+}
+
+fn known_templates() -> [KnownTemplate; 4] {
+    [
+        known::<RankCardBackgroundTemplate>(),
+        known::<RankCardTemplate>(),
+        known::<LeaderboardTemplate>(),
+        known::<RoleRewardBaseTemplate>(),
+    ]
+}
+
+/// Renders `T::sample()` through the runtime copy and checks the result is an SVG
+/// usvg can parse, so a typo'd field or broken markup never reaches a request.
+fn validate<T: SvgTemplate>(env: &Environment<'static>) -> Result<(), String> {
+    let svg = env
+        .get_template(T::FILE)
+        .and_then(|template| template.render(T::sample()))
+        .map_err(|e| format!("{:#}", e))?;
+    usvg::Tree::from_str(&svg, &usvg::Options::default())
+        .map(|_| ())
+        .map_err(|e| format!("rendered sample is not valid SVG: {}", e))
+}
+
+/// Runtime templates that passed validation, plus the version they add up to.
+struct Loaded {
+    env: Environment<'static>,
+    overrides: HashSet<&'static str>,
+    version: String,
+}
+
+/// Askama-compatible runtime environment: XML escaping for every template, strict
+/// undefined (a misspelled field fails validation instead of rendering blank) and
+/// the `.len()` / `.is_empty()` calls the compiled templates use on lists.
+fn new_environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_unknown_method_callback(|_, value, method, args| match (method, value.len()) {
+        ("len", Some(len)) if args.is_empty() => Ok(Value::from(len)),
+        ("is_empty", Some(len)) if args.is_empty() => Ok(Value::from(len == 0)),
+        _ => Err(Error::from(ErrorKind::UnknownMethod)),
+    });
+    env
+}
+
+/// Loads every known template found in `dir`. A file that is missing, unreadable
+/// or fails validation is left out, so that template falls back to its compiled-in
+/// version until the file is fixed.
+fn load(dir: &Path) -> Loaded {
+    let mut env = new_environment();
+    let mut overrides = HashSet::new();
+    let mut sources = Vec::new();
+
+    for known in known_templates() {
+        let path = dir.join(known.file);
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                sources.push(known.compiled.to_string());
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("Template {} unreadable, using compiled-in version: {}", path.display(), e);
+                sources.push(known.compiled.to_string());
+                continue;
+            }
+        };
+
+        let mut candidate = new_environment();
+        let checked = candidate
+            .add_template_owned(known.file, source.clone())
+            .map_err(|e| format!("{:#}", e))
+            .and_then(|_| (known.validate)(&candidate));
+
+        match checked.and_then(|_| env.add_template_owned(known.file, source.clone()).map_err(|e| e.to_string())) {
+            Ok(()) => {
+                overrides.insert(known.file);
+                sources.push(source);
+            }
+            Err(e) => {
+                metrics::counter!("renderer_template_load_failures_total", "template" => known.file).increment(1);
+                tracing::error!("Template {} rejected, using compiled-in version: {}", path.display(), e);
+                sources.push(known.compiled.to_string());
+            }
+        }
+    }
+
+    let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+    metrics::gauge!("renderer_runtime_templates_loaded").set(overrides.len() as f64);
+    Loaded {
+        env,
+        overrides,
+        version: cache::template_version(&sources),
+    }
+}
+
+/// Renders the SVG templates in template.rs.
+///
+/// By default every template is the Askama version compiled into the binary.
+/// With `TEMPLATE_DIR` set, same-named files in that directory override them:
+/// they are validated against the context structs at load time, reloaded when
+/// the directory changes, and any template that fails to load or render falls
+/// back to its compiled-in version.
+pub struct TemplateEngine {
+    runtime: Option<Arc<RwLock<Arc<Loaded>>>>,
+    version: watch::Sender<String>,
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl TemplateEngine {
+    /// Compiled-in templates only.
+    pub fn compiled() -> Self {
+        let sources: Vec<&str> = known_templates().iter().map(|t| t.compiled).collect();
+        let (version, _) = watch::channel(cache::template_version(&sources));
+        Self {
+            runtime: None,
+            version,
+            _watcher: None,
+        }
+    }
+
+    /// Loads runtime templates from `dir` and watches it for changes.
+    /// Must be called from within the tokio runtime.
+    pub fn runtime(dir: PathBuf) -> anyhow::Result<Self> {
+        let loaded = load(&dir);
+        tracing::info!("Loaded {} runtime template(s) from {}", loaded.overrides.len(), dir.display());
+        let (version, _) = watch::channel(loaded.version.clone());
+        let runtime = Arc::new(RwLock::new(Arc::new(loaded)));
+
+        let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                    let _ = changed_tx.send(());
+                }
+            }
+        })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        let reload_target = runtime.clone();
+        let reload_version = version.clone();
+        tokio::spawn(async move {
+            while changed_rx.recv().await.is_some() {
+                tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                while changed_rx.try_recv().is_ok() {}
+
+                let dir = dir.clone();
+                let Ok(loaded) = tokio::task::spawn_blocking(move || load(&dir)).await else { continue };
+                tracing::info!("Reloaded templates ({} runtime, version {})", loaded.overrides.len(), loaded.version);
+                metrics::counter!("renderer_template_reloads_total").increment(1);
+                let version = loaded.version.clone();
+                *reload_target.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(loaded);
+                reload_version.send_replace(version);
+            }
+        });
+
+        Ok(Self {
+            runtime: Some(runtime),
+            version,
+            _watcher: Some(watcher),
+        })
+    }
+
+    /// Runtime mode if `TEMPLATE_DIR` is set, compiled-in templates otherwise.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("TEMPLATE_DIR") {
+            Ok(dir) if !dir.is_empty() => Self::runtime(PathBuf::from(dir)),
+            _ => Ok(Self::compiled()),
+        }
+    }
+
+    /// Hash of the template sources currently in effect.
+    pub fn version(&self) -> String {
+        self.version.borrow().clone()
+    }
+
+    /// Follows `version` across reloads.
+    pub fn subscribe(&self) -> watch::Receiver<String> {
+        self.version.subscribe()
+    }
+
+    pub fn render<T: SvgTemplate>(&self, context: &T) -> Result<String, RenderError> {
+        if let Some(runtime) = &self.runtime {
+            let loaded = runtime.read().unwrap_or_else(|e| e.into_inner()).clone();
+            if loaded.overrides.contains(T::FILE) {
+                match loaded.env.get_template(T::FILE).and_then(|template| template.render(context)) {
+                    Ok(svg) => return Ok(svg),
+                    Err(e) => {
+                        metrics::counter!("renderer_template_render_fallbacks_total", "template" => T::FILE).increment(1);
+                        tracing::warn!("Runtime template {} failed, using compiled-in version: {:#}", T::FILE, e);
+                    }
+                }
+            }
+        }
+        Ok(context.render()?)
+    }
+}
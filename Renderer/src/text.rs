@@ -0,0 +1,607 @@
+use std::sync::Arc;
+use tiny_skia::Pixmap;
+use unicode_script::{Script, UnicodeScript};
+use unicode_segmentation::UnicodeSegmentation;
+use usvg::fontdb::{Database, Family, Query, Stretch, Style, Weight, ID};
+
+use crate::fonts::FontRegistry;
+use crate::render::RasterLayer;
+use crate::template::TemplateNameRun;
+
+/// Appended to truncated text.
+pub const ELLIPSIS: &str = "...";
+
+/// A CSS `font-family` list plus weight, as written on an SVG `<text>` element.
+#[derive(Debug, Clone)]
+pub struct FontStack {
+    families: Vec<String>,
+    weight: u16,
+}
+
+impl FontStack {
+    /// Parses a `font-family` list at normal weight, exactly as usvg 0.38 does:
+    /// one quote is stripped from each end *before* trimming, so a quoted name
+    /// after `, ` keeps its quotes and matches no installed face.
+    pub fn parse(css: &str) -> Self {
+        let families = css
+            .split(',')
+            .map(|family| {
+                let family = family.strip_prefix(['\'', '"']).unwrap_or(family);
+                let family = family.strip_suffix(['\'', '"']).unwrap_or(family);
+                family.trim().to_string()
+            })
+            .filter(|family| !family.is_empty())
+            .collect();
+        Self { families, weight: 400 }
+    }
+
+    pub fn bold(mut self) -> Self {
+        self.weight = 700;
+        self
+    }
+
+    pub fn weight(&self) -> u16 {
+        self.weight
+    }
+}
+
+/// One shaped glyph: the byte offset of its cluster in the measured text, the
+/// glyph in its face (0 = missing) and its advance in px.
+#[derive(Clone, Copy)]
+struct Glyph {
+    cluster: usize,
+    glyph_id: u32,
+    advance: f32,
+}
+
+/// Text measurement that shapes through the same fontdb, rustybuzz and font
+/// selection usvg uses when it converts `<text>` to paths, so layout math
+/// matches the final raster: kerning, ligatures, combining marks, bidi runs and
+/// per-glyph font fallback included.
+pub struct TextLayout {
+    fontdb: Arc<Database>,
+}
+
+impl TextLayout {
+    pub fn new(fontdb: Arc<Database>) -> Self {
+        Self { fontdb }
+    }
+
+    /// Rendered width in px of `text` set in `font_stack` at `size` px.
+    pub fn measure(&self, text: &str, font_stack: &FontStack, size: f32) -> f32 {
+        self.shape(text, font_stack, size).iter().map(|g| g.advance).sum()
+    }
+
+    /// Shortens `text` to fit in `max_width` px, cutting only between extended
+    /// grapheme clusters (never inside an emoji ZWJ sequence, flag or accented
+    /// letter) and appending an ellipsis. Binary-searches the cut point, so a long
+    /// name costs O(log n) measurements. Returns the text and its width.
+    pub fn ellipsize(&self, text: &str, font_stack: &FontStack, size: f32, max_width: f32) -> (String, f32) {
+        let width = self.measure(text, font_stack, size);
+        if width <= max_width || text.is_empty() {
+            return (text.to_string(), width);
+        }
+
+        let boundaries: Vec<usize> = text.grapheme_indices(true).map(|(i, _)| i).collect();
+        let candidate = |graphemes: usize| {
+            let cut = boundaries.get(graphemes).copied().unwrap_or(text.len());
+            let truncated = format!("{}{}", text[..cut].trim_end(), ELLIPSIS);
+            let width = self.measure(&truncated, font_stack, size);
+            (truncated, width)
+        };
+
+        // Largest grapheme count whose truncation still fits; 0 if none does.
+        let (mut lo, mut hi) = (0, boundaries.len() - 1);
+        while lo < hi {
+            let mid = (lo + hi).div_ceil(2);
+            if candidate(mid).1 <= max_width {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        candidate(lo)
+    }
+
+    /// Breaks `text` into at most two lines: the first takes as many whole words
+    /// as fit in `first_width` (whole graphemes if even the first word is too
+    /// long), the rest goes on a second line ellipsized to `second_width`.
+    pub fn wrap_two_lines(
+        &self,
+        text: &str,
+        font_stack: &FontStack,
+        size: f32,
+        first_width: f32,
+        second_width: f32,
+    ) -> Vec<String> {
+        if text.is_empty() || self.measure(text, font_stack, size) <= first_width {
+            return vec![text.to_string()];
+        }
+
+        let word_cuts: Vec<usize> = text.match_indices(' ').map(|(i, _)| i).collect();
+        let grapheme_cuts: Vec<usize> = text.grapheme_indices(true).map(|(i, _)| i).skip(1).collect();
+        let cut = self
+            .longest_fitting_prefix(text, &word_cuts, font_stack, size, first_width)
+            .or_else(|| self.longest_fitting_prefix(text, &grapheme_cuts, font_stack, size, first_width))
+            // Not even one grapheme fits: still put one there rather than an empty line.
+            .or_else(|| grapheme_cuts.first().copied())
+            .unwrap_or(text.len());
+
+        let (second, _) = self.ellipsize(text[cut..].trim_start(), font_stack, size, second_width);
+        vec![text[..cut].trim_end().to_string(), second]
+    }
+
+    /// True if the face `font_stack` resolves to has a glyph for every
+    /// non-whitespace character of `text`, i.e. usvg would need no fallback.
+    pub fn covers(&self, text: &str, font_stack: &FontStack) -> bool {
+        let Some(id) = self.resolve(font_stack) else {
+            return false;
+        };
+        self.fontdb
+            .with_face_data(id, |data, index| {
+                rustybuzz::ttf_parser::Face::parse(data, index).is_ok_and(|face| {
+                    text.chars().filter(|c| !c.is_whitespace()).all(|c| face.glyph_index(c).is_some())
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Family of the installed face with glyphs for the most characters of
+    /// `text`, preferring the face closest to `weight` on a tie.
+    pub fn best_family(&self, text: &str, weight: u16) -> Option<String> {
+        let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+        self.fontdb
+            .faces()
+            .filter_map(|face| {
+                let covered = self
+                    .fontdb
+                    .with_face_data(face.id, |data, index| {
+                        let parsed = rustybuzz::ttf_parser::Face::parse(data, index).ok()?;
+                        Some(chars.iter().filter(|&&c| parsed.glyph_index(c).is_some()).count())
+                    })
+                    .flatten()?;
+                let family = face.families.first()?.0.clone();
+                (covered > 0).then_some((covered, face.weight.0.abs_diff(weight), family))
+            })
+            .min_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)))
+            .map(|(_, _, family)| family)
+    }
+
+    /// Largest of the ascending byte offsets `cuts` whose prefix fits in `max_width`.
+    fn longest_fitting_prefix(
+        &self,
+        text: &str,
+        cuts: &[usize],
+        font_stack: &FontStack,
+        size: f32,
+        max_width: f32,
+    ) -> Option<usize> {
+        let fitting = cuts.partition_point(|&cut| self.measure(text[..cut].trim_end(), font_stack, size) <= max_width);
+        fitting.checked_sub(1).map(|i| cuts[i])
+    }
+
+    /// Shapes `text` the way usvg will: the first installed family in the stack
+    /// (then serif) shapes everything, and each still-missing glyph triggers a
+    /// database-wide search for a face that has it, whose glyphs are swapped in.
+    /// Whitespace must already be collapsed, as the SVG would do.
+    fn shape(&self, text: &str, font_stack: &FontStack, size: f32) -> Vec<Glyph> {
+        if text.is_empty() {
+            return Vec::new();
+        }
+        let Some(primary) = self.resolve(font_stack) else {
+            return Vec::new();
+        };
+
+        let mut glyphs = self.shape_with(text, primary, size).unwrap_or_default();
+        let mut used = vec![primary];
+        while let Some(missing) = glyphs.iter().find(|g| g.glyph_id == 0) {
+            let c = text[missing.cluster..].chars().next().unwrap_or(' ');
+            let Some(fallback) = self.find_font_for_char(c, &used) else { break };
+            let fallback_glyphs = self.shape_with(text, fallback, size).unwrap_or_default();
+
+            if fallback_glyphs.iter().all(|g| g.glyph_id != 0) {
+                glyphs = fallback_glyphs;
+                break;
+            }
+            if fallback_glyphs.len() != glyphs.len() {
+                break;
+            }
+            for (glyph, fallback_glyph) in glyphs.iter_mut().zip(&fallback_glyphs) {
+                if glyph.glyph_id == 0 && fallback_glyph.glyph_id != 0 {
+                    *glyph = *fallback_glyph;
+                }
+            }
+            used.push(fallback);
+        }
+
+        glyphs
+    }
+
+    fn resolve(&self, font_stack: &FontStack) -> Option<ID> {
+        let mut families: Vec<Family> = font_stack
+            .families
+            .iter()
+            .map(|name| match name.as_str() {
+                "serif" => Family::Serif,
+                "sans-serif" => Family::SansSerif,
+                "cursive" => Family::Cursive,
+                "fantasy" => Family::Fantasy,
+                "monospace" => Family::Monospace,
+                _ => Family::Name(name),
+            })
+            .collect();
+        families.push(Family::Serif);
+
+        self.fontdb.query(&Query {
+            families: &families,
+            weight: Weight(font_stack.weight),
+            stretch: Stretch::Normal,
+            style: Style::Normal,
+        })
+    }
+
+    /// Same search (and same style check) as usvg's own fallback.
+    fn find_font_for_char(&self, c: char, exclude: &[ID]) -> Option<ID> {
+        let base = self.fontdb.face(exclude[0])?;
+        self.fontdb
+            .faces()
+            .filter(|face| !exclude.contains(&face.id))
+            .filter(|face| !(base.style != face.style && base.weight != face.weight && base.stretch != face.stretch))
+            .find(|face| self.has_char(face.id, c))
+            .map(|face| face.id)
+    }
+
+    fn has_char(&self, id: ID, c: char) -> bool {
+        self.fontdb
+            .with_face_data(id, |data, index| {
+                rustybuzz::ttf_parser::Face::parse(data, index).is_ok_and(|face| face.glyph_index(c).is_some())
+            })
+            .unwrap_or(false)
+    }
+
+    /// Shapes every bidi run of `text` with one face.
+    fn shape_with(&self, text: &str, id: ID, size: f32) -> Option<Vec<Glyph>> {
+        self.fontdb.with_face_data(id, |data, index| {
+            let face = rustybuzz::Face::from_slice(data, index)?;
+            let scale = size / face.units_per_em() as f32;
+
+            let bidi = unicode_bidi::BidiInfo::new(text, Some(unicode_bidi::Level::ltr()));
+            let paragraph = &bidi.paragraphs[0];
+            let (levels, runs) = bidi.visual_runs(paragraph, paragraph.range.clone());
+
+            let mut glyphs = Vec::new();
+            for run in runs {
+                let sub_text = &text[run.clone()];
+                if sub_text.is_empty() {
+                    continue;
+                }
+                let mut buffer = rustybuzz::UnicodeBuffer::new();
+                buffer.push_str(sub_text);
+                buffer.set_direction(if levels[run.start].is_rtl() {
+                    rustybuzz::Direction::RightToLeft
+                } else {
+                    rustybuzz::Direction::LeftToRight
+                });
+
+                let output = rustybuzz::shape(&face, &[], buffer);
+                for (info, pos) in output.glyph_infos().iter().zip(output.glyph_positions()) {
+                    glyphs.push(Glyph {
+                        cluster: run.start + info.cluster as usize,
+                        glyph_id: info.glyph_id,
+                        advance: pos.x_advance as f32 * scale,
+                    });
+                }
+            }
+            Some(glyphs)
+        })?
+    }
+}
+
+/// Splits `text` into maximal runs of one Unicode script. Characters shared
+/// between scripts (spaces, digits, punctuation, combining marks) stay in the
+/// run they follow, or join the first real script run when they lead.
+pub fn script_runs(text: &str) -> Vec<(Script, &str)> {
+    let mut runs: Vec<(Script, &str)> = Vec::new();
+    let mut start = 0;
+    let mut current = Script::Common;
+
+    for (i, c) in text.char_indices() {
+        let script = c.script();
+        if matches!(script, Script::Common | Script::Inherited | Script::Unknown) || script == current {
+            continue;
+        }
+        if current == Script::Common {
+            current = script;
+            continue;
+        }
+        runs.push((current, &text[start..i]));
+        start = i;
+        current = script;
+    }
+    if start < text.len() {
+        runs.push((current, &text[start..]));
+    }
+    runs
+}
+
+/// Collapses runs of XML whitespace to one space and trims the ends, as SVG does
+/// for `<text>` content without `xml:space="preserve"`. Unicode spaces such as
+/// U+3000 are content and survive.
+pub fn collapse_whitespace(text: &str) -> String {
+    text.split([' ', '\t', '\n', '\r'])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A display name piece with its emoji image resolved.
+pub enum NamePiece {
+    Text(String),
+    Image(Arc<Pixmap>),
+}
+
+/// Space kept either side of an inline emoji.
+const INLINE_EMOJI_MARGIN: f32 = 3.0;
+
+/// One piece of a line in reading (logical) order: text in a `font-family`,
+/// an inline image of a given advance, or a gap standing in for trimmed space.
+enum Atom {
+    Text { text: String, font: String },
+    Image { image: Arc<Pixmap>, advance: f32 },
+    Gap(f32),
+}
+
+/// Places `atoms` in visual order, as the Unicode bidi algorithm would display
+/// them: the paragraph direction comes from the first strong character, images
+/// count as neutral object replacement characters, text is split wherever its
+/// embedding level changes, and the pieces are reordered by rule L2. Each text
+/// piece is then one level throughout, which usvg draws correctly on its own.
+/// Returns each piece with its x offset from the start of the line, and the
+/// line's total width. Text is measured bold at `size`.
+fn place_bidi(layout: &TextLayout, atoms: Vec<Atom>, size: f32) -> (Vec<(Atom, f32)>, f32) {
+    let mut logical = String::new();
+    let mut ranges = Vec::with_capacity(atoms.len());
+    for atom in &atoms {
+        let start = logical.len();
+        match atom {
+            Atom::Text { text, .. } => logical.push_str(text),
+            Atom::Image { .. } => logical.push('\u{FFFC}'),
+            Atom::Gap(_) => logical.push(' '),
+        }
+        ranges.push(start..logical.len());
+    }
+    if logical.is_empty() {
+        return (Vec::new(), 0.0);
+    }
+
+    let bidi = unicode_bidi::BidiInfo::new(&logical, None);
+    let paragraph = &bidi.paragraphs[0];
+    let levels = bidi.reordered_levels(paragraph, paragraph.range.clone());
+
+    // Split text at level changes and measure every piece.
+    let mut pieces: Vec<(unicode_bidi::Level, Atom, f32)> = Vec::new();
+    for (atom, range) in atoms.into_iter().zip(ranges) {
+        match atom {
+            Atom::Text { text, font } => {
+                let stack = FontStack::parse(&font).bold();
+                let mut start = 0;
+                for (i, _) in text.char_indices().skip(1).chain(std::iter::once((text.len(), ' '))) {
+                    if i < text.len() && levels[range.start + i] == levels[range.start + start] {
+                        continue;
+                    }
+                    // Spaces at the edge of a piece become gaps: SVG would trim
+                    // them, and usvg would set them on the wrong side of RTL text.
+                    let level = levels[range.start + start];
+                    let part = &text[start..i];
+                    let core = part.trim_matches(' ');
+                    let leading = part.len() - part.trim_start_matches(' ').len();
+                    let trailing = if core.is_empty() { 0 } else { part.len() - part.trim_end_matches(' ').len() };
+                    let space = layout.measure(" ", &stack, size);
+                    if leading > 0 {
+                        pieces.push((level, Atom::Gap(space * leading as f32), space * leading as f32));
+                    }
+                    if !core.is_empty() {
+                        let width = layout.measure(core, &stack, size);
+                        pieces.push((level, Atom::Text { text: core.to_string(), font: font.clone() }, width));
+                    }
+                    if trailing > 0 {
+                        pieces.push((level, Atom::Gap(space * trailing as f32), space * trailing as f32));
+                    }
+                    start = i;
+                }
+            }
+            Atom::Image { image, advance } => pieces.push((levels[range.start], Atom::Image { image, advance }, advance)),
+            Atom::Gap(width) => pieces.push((levels[range.start], Atom::Gap(width), width)),
+        }
+    }
+
+    // L2: from the highest level down to the lowest odd one, reverse every run
+    // of pieces at that level or higher.
+    let highest = pieces.iter().map(|(level, ..)| level.number()).max().unwrap_or(0);
+    let lowest_odd = pieces.iter().map(|(level, ..)| level.number()).min().unwrap_or(0) | 1;
+    for level in (lowest_odd..=highest).rev() {
+        let mut i = 0;
+        while i < pieces.len() {
+            if pieces[i].0.number() < level {
+                i += 1;
+                continue;
+            }
+            let end = pieces[i..].iter().position(|(l, ..)| l.number() < level).map_or(pieces.len(), |n| i + n);
+            pieces[i..end].reverse();
+            i = end;
+        }
+    }
+
+    let mut cursor = 0.0;
+    let placed = pieces
+        .into_iter()
+        .map(|(_, atom, width)| {
+            let x = cursor;
+            cursor += width;
+            (atom, x)
+        })
+        .collect();
+    (placed, cursor)
+}
+
+/// Splits one line of `text` in `font` into pieces of a single direction,
+/// placed in visual order as `place_bidi` does; returns each with its x offset.
+pub fn bidi_line(layout: &TextLayout, text: String, font: &str, size: f32) -> Vec<(String, f32)> {
+    let (placed, _) = place_bidi(layout, vec![Atom::Text { text, font: font.to_string() }], size);
+    placed
+        .into_iter()
+        .filter_map(|(atom, x)| match atom {
+            Atom::Text { text, .. } => Some((text, x)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A display name laid out for a template: text spans for the SVG, inline
+/// emoji with their x for raster layers, and the total width.
+pub struct NameLayout {
+    pub runs: Vec<TemplateNameRun>,
+    pub images: Vec<(f32, Arc<Pixmap>)>,
+    pub width: f32,
+}
+
+impl NameLayout {
+    /// Raster layers for the inline emoji, `size` px square with their top at `y`.
+    pub fn emoji_layers(images: Vec<(f32, Arc<Pixmap>)>, y: f32, size: f32) -> impl Iterator<Item = RasterLayer> {
+        images.into_iter().map(move |(x, image)| RasterLayer::new(image, x, y, size, size))
+    }
+}
+
+/// Lays name pieces out from `x`, emoji inline at the text `size`, and cuts
+/// the name with an ellipsis where it would pass `max_width`. Fitting happens in
+/// reading order, then `place_bidi` arranges the result, so a right-to-left
+/// name loses its logical end and shows the ellipsis on its left. Text is split
+/// into script runs, each in the font the registry picks for it within
+/// `template`. Whitespace at the edge of a text piece becomes a gap, since SVG
+/// would trim it from the tspan.
+pub fn layout_name(
+    fonts: &FontRegistry,
+    layout: &TextLayout,
+    pieces: Vec<NamePiece>,
+    template: &str,
+    size: f32,
+    x: f64,
+    max_width: f32,
+) -> NameLayout {
+    let base = fonts.stack(template, "username");
+    let font = &FontStack::parse(base).bold();
+    let is_space = |c: char| matches!(c, ' ' | '\t' | '\n' | '\r');
+    let space = layout.measure("a a", font, size) - layout.measure("aa", font, size);
+    let count = pieces.len();
+    let mut atoms = Vec::new();
+    let mut cursor = 0.0;
+
+    'pieces: for (i, piece) in pieces.into_iter().enumerate() {
+        match piece {
+            NamePiece::Text(raw) => {
+                if !atoms.is_empty() && raw.starts_with(is_space) {
+                    atoms.push(Atom::Gap(space));
+                    cursor += space;
+                }
+                let text = collapse_whitespace(&raw);
+                if text.is_empty() {
+                    continue;
+                }
+                for run in fonts.font_runs(layout, &text, template, base, "username_system", true) {
+                    let run_font = FontStack::parse(&run.font).bold();
+                    let (fitted, width) = layout.ellipsize(run.text, &run_font, size, (max_width - cursor).max(0.0));
+                    if fitted == run.text {
+                        atoms.push(Atom::Text { text: fitted, font: run.font });
+                        cursor += width;
+                        continue;
+                    }
+                    let (mut fitted, mut font) = (fitted, run.font);
+                    // Nothing of this run fits and neither does a lone ellipsis:
+                    // cut into the text before it instead.
+                    if fitted == ELLIPSIS && cursor + width > max_width {
+                        while let Some(Atom::Gap(gap)) = atoms.last() {
+                            cursor -= gap;
+                            atoms.pop();
+                        }
+                        if let Some(Atom::Text { text, font: prev }) = atoms.pop() {
+                            let prev_font = FontStack::parse(&prev).bold();
+                            let room = max_width - cursor + layout.measure(&text, &prev_font, size);
+                            let with_ellipsis = format!("{}{}", text, ELLIPSIS);
+                            fitted = layout.ellipsize(&with_ellipsis, &prev_font, size, room.max(0.0)).0;
+                            font = prev;
+                        }
+                    }
+                    // The ellipsis is its own atom so bidi can put it at the logical end.
+                    let kept = fitted.strip_suffix(ELLIPSIS).unwrap_or(&fitted);
+                    if !kept.is_empty() {
+                        atoms.push(Atom::Text { text: kept.to_string(), font: font.clone() });
+                    }
+                    atoms.push(Atom::Text { text: ELLIPSIS.to_string(), font });
+                    break 'pieces;
+                }
+                if i + 1 < count && raw.ends_with(is_space) {
+                    atoms.push(Atom::Gap(space));
+                    cursor += space;
+                }
+            }
+            NamePiece::Image(image) => {
+                let advance = size + 2.0 * INLINE_EMOJI_MARGIN;
+                if cursor + advance > max_width {
+                    if cursor + layout.measure(ELLIPSIS, font, size) <= max_width {
+                        atoms.push(Atom::Text { text: ELLIPSIS.to_string(), font: base.to_string() });
+                    }
+                    break;
+                }
+                atoms.push(Atom::Image { image, advance });
+                cursor += advance;
+            }
+        }
+    }
+
+    let (placed, width) = place_bidi(layout, atoms, size);
+    let mut layout = NameLayout { runs: Vec::new(), images: Vec::new(), width };
+    for (atom, offset) in placed {
+        match atom {
+            Atom::Text { text, font } => layout.runs.push(TemplateNameRun { text, font, x: x + offset as f64 }),
+            Atom::Image { image, .. } => layout.images.push((x as f32 + offset + INLINE_EMOJI_MARGIN, image)),
+            Atom::Gap(_) => {}
+        }
+    }
+    layout
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text pieces of one line in visual (left-to-right) order.
+    fn visual(text: &str) -> Vec<String> {
+        let mut db = Database::new();
+        db.load_system_fonts();
+        let layout = TextLayout::new(Arc::new(db));
+        let pieces = bidi_line(&layout, text.to_string(), "sans-serif", 30.0);
+        assert!(pieces.windows(2).all(|pair| pair[0].1 <= pair[1].1), "offsets must increase left to right");
+        pieces.into_iter().map(|(text, _)| text).collect()
+    }
+
+    #[test]
+    fn rtl_word_inside_ltr_line_keeps_its_place() {
+        assert_eq!(visual("abc אבג def"), ["abc", "אבג", "def"]);
+    }
+
+    #[test]
+    fn ltr_word_inside_rtl_line_is_reordered() {
+        assert_eq!(visual("אבג abc דהו"), ["דהו", "abc", "אבג"]);
+    }
+
+    #[test]
+    fn numbers_inside_rtl_line_stay_left_to_right() {
+        assert_eq!(visual("אבג 123 דהו"), ["דהו", "123", "אבג"]);
+        assert_eq!(visual("אבג abc 123 דהו"), ["דהו", "abc 123", "אבג"]);
+    }
+
+    #[test]
+    fn plain_ltr_line_is_one_piece() {
+        assert_eq!(visual("Goku 9000"), ["Goku 9000"]);
+    }
+}
@@ -33,6 +33,7 @@ module.exports = {
 
     // 3. Prepare Data for Image Service
     const rankData = {
+      userId: targetUser.id,
       username: targetUser.username,
       avatarUrl: inGuild ? targetUser.displayAvatarURL({ extension: 'png', size: 512 }) : targetUser.defaultAvatarURL,
       // Pill 1 Data
@@ -41,24 +41,30 @@ class AssetService {
   }
 
   /**
-   * Fetches an asset Buffer from a Discord Message Link.
+   * Resolves a Discord Message Link to the CDN URL of its first attachment.
    */
-  static async fetchAssetFromLink(client, messageLink) {
-    try {
-      const match = messageLink.match(MESSAGE_LINK_REGEX);
-      if (!match) throw new Error('Invalid Discord Message Link format');
+  static async resolveAttachmentUrl(client, messageLink) {
+    const match = messageLink.match(MESSAGE_LINK_REGEX);
+    if (!match) throw new Error('Invalid Discord Message Link format');
 
-      const [, _guildId, channelId, messageId] = match;
+    const [, _guildId, channelId, messageId] = match;
 
-      const channel = await client.channels.fetch(channelId);
-      const message = await channel.messages.fetch(messageId);
+    const channel = await client.channels.fetch(channelId);
+    const message = await channel.messages.fetch(messageId);
 
-      if (!message || message.attachments.size === 0) {
-        throw new Error('Message or Attachment not found');
-      }
+    if (!message || message.attachments.size === 0) {
+      throw new Error('Message or Attachment not found');
+    }
+
+    return message.attachments.first().url;
+  }
 
-      const attachment = message.attachments.first();
-      const url = attachment.url;
+  /**
+   * Fetches an asset Buffer from a Discord Message Link.
+   */
+  static async fetchAssetFromLink(client, messageLink) {
+    try {
+      const url = await this.resolveAttachmentUrl(client, messageLink);
 
       // Download with a stricter timeout (15s) to fail fast if stuck
       const response = await fetch(url, { signal: AbortSignal.timeout(15000) });
@@ -2,12 +2,10 @@
 
 const fs = require('fs');
 const path = require('path');
-const sharp = require('sharp');
-const { Worker } = require('worker_threads');
-const { execFile } = require('child_process');
-const gifsicle = '/usr/bin/gifsicle'; // System binary (like ffmpeg)
 const { AssetService } = require('./AssetService');
 const { DatabaseService } = require('./DatabaseService');
+const ImageService = require('./ImageService');
+const MetricsService = require('./MetricsService');
 const logger = require('../lib/logger');
 
 // Setup RAM Disk (Extremely fast I/O)
@@ -15,142 +13,100 @@ const USE_RAM_DISK = process.platform === 'linux' && fs.existsSync('/dev/shm');
 const TEMP_DIR = USE_RAM_DISK ? '/dev/shm' : path.join(process.cwd(), 'temp');
 if (!fs.existsSync(TEMP_DIR)) fs.mkdirSync(TEMP_DIR, { recursive: true });
 
-// 2-vCPU Limit out of the 4 available on e2-standard-4
-const MAX_WORKERS = 2;
+// Templates are authored at 10 fps
+const FRAME_DELAY_MS = 100;
+const ICON_SIZE = 60;
 
 class GifService {
-  static queue = [];
-  static activeWorkers = 0;
-
+  /**
+   * Renders the clan GIF through the Rust renderer's /render/clan-gif route and
+   * writes it to a temp file. Returns the file path; the caller deletes it.
+   */
   static async generateClanGif(client, clanRoleIds, clanCount) {
-    return new Promise((resolve, reject) => {
-      this.queue.push({ client, clanRoleIds, clanCount, resolve, reject });
-      this.processQueue();
+    const template = await DatabaseService.getGifTemplate(clanCount);
+    if (!template) throw new Error(`No template for ${clanCount} clans`);
+
+    // The renderer reads frames from assets/gif_templates/<count>/<name>/frames/*.png
+    const templateDir = path.join(String(clanCount), template.name);
+    const coordsPath = path.join(process.cwd(), 'assets', 'gif_templates', templateDir, 'coords.json');
+    if (!fs.existsSync(coordsPath)) throw new Error('Missing coords.json');
+    const coords = JSON.parse(fs.readFileSync(coordsPath, 'utf8'));
+
+    const effectiveRoles = clanCount === 2 ? [clanRoleIds[0]] : clanRoleIds;
+    const iconUrls = await this.resolveClanIconUrls(client, effectiveRoles);
+
+    const payload = {
+      template: templateDir.split(path.sep).join('/'),
+      coords,
+      clans: iconUrls.map((iconUrl, index) => ({
+        icon_url: iconUrl,
+        visible: this.mergeRanges(this.getVisibilityRanges(clanCount, index)),
+      })),
+      icon_size: ICON_SIZE,
+      frame_delay_ms: FRAME_DELAY_MS,
+    };
+
+    const url = ImageService.rendererUrl.replace('/render', '/render/clan-gif');
+    const timer = MetricsService.rendererRequestDuration.startTimer();
+    const response = await fetch(url, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(payload),
+      signal: AbortSignal.timeout(60000),
     });
-  }
-
-  static async processQueue() {
-    // Stop if queue is empty or we are using our 2 allocated vCores
-    if (this.queue.length === 0 || this.activeWorkers >= MAX_WORKERS) return;
-
-    const jobToRun = this.queue.shift();
-    this.activeWorkers++;
+    timer();
+    if (!response.ok) throw await ImageService.rendererError(response, 'Clan GIF renderer error:');
 
-    this._runWorker(jobToRun)
-      .then(() => {
-        this.activeWorkers--;
-        this.processQueue(); // Pull next job immediately
-      })
-      .catch((err) => {
-        logger.error('[GifService] Generation Error:', err);
-        this.activeWorkers--;
-        this.processQueue();
-      });
+    const outputPath = path.join(TEMP_DIR, `clan_${Date.now()}.gif`);
+    fs.writeFileSync(outputPath, Buffer.from(await response.arrayBuffer()));
+    return outputPath;
+  }
 
-    // If we only started 1 worker and the queue has more, fire up the 2nd one
-    if (this.activeWorkers < MAX_WORKERS && this.queue.length > 0) {
-      this.processQueue();
+  /**
+   * Frame ranges ([start, end], inclusive) during which each clan's icon is on screen.
+   */
+  static getVisibilityRanges(clanCount, roleIndex) {
+    if (clanCount === 2) return roleIndex === 0 ? [[0, 999]] : [];
+    if (clanCount === 4) {
+      const ranges = [];
+      if ([1, 2, 3].includes(roleIndex)) ranges.push([0, 20]);
+      if ([0, 3].includes(roleIndex)) ranges.push([21, 40]);
+      if ([0].includes(roleIndex)) ranges.push([41, 66]);
+      if ([0, 1, 2].includes(roleIndex)) ranges.push([67, 95]);
+      return ranges;
     }
+    return [];
   }
 
-  static async _runWorker(task) {
-    const { client, clanRoleIds, clanCount, resolve, reject } = task;
-    const tempFiles = [];
-
-    try {
-      const template = await DatabaseService.getGifTemplate(clanCount);
-      if (!template) throw new Error(`No template for ${clanCount} clans`);
-
-      // EXPECTATION: You must convert the background sequence to a 'template.mp4' for maximum speed
-      const templatePath = path.join(
-        process.cwd(),
-        'assets',
-        'gif_templates',
-        String(clanCount),
-        template.name,
-        'template.mp4'
-      );
-      const coordsPath = path.join(
-        process.cwd(),
-        'assets',
-        'gif_templates',
-        String(clanCount),
-        template.name,
-        'coords.json'
-      );
-
-      if (!fs.existsSync(coordsPath)) throw new Error('Missing coords.json');
-      const coordsData = JSON.parse(fs.readFileSync(coordsPath, 'utf8'));
-
-      const effectiveRoles = clanCount === 2 ? [clanRoleIds[0]] : clanRoleIds;
-      const iconBuffers = await this.prepareClanIcons(client, effectiveRoles);
-      const iconPaths = [];
-
-      // Write icons to RAM Disk
-      for (let i = 0; i < iconBuffers.length; i++) {
-        const p = path.join(TEMP_DIR, `icon_${Date.now()}_${i}.png`);
-        if (iconBuffers[i]) {
-          await sharp(iconBuffers[i]).toFile(p);
-        } else {
-          await sharp({ create: { width: 1, height: 1, channels: 4, background: { r: 0, g: 0, b: 0, alpha: 0 } } })
-            .png()
-            .toFile(p);
-        }
-        iconPaths.push(p);
-        tempFiles.push(p);
-      }
-
-      const outputPath = path.join(TEMP_DIR, `out_${Date.now()}.gif`);
-      tempFiles.push(outputPath);
-
-      // Spin up the FFmpeg Worker
-      const worker = new Worker(path.join(__dirname, '../workers/gifWorker.js'), {
-        workerData: { templatePath, iconPaths, outputPath, clanCount, coords: coordsData },
-      });
-
-      await new Promise((wRes, wErr) => {
-        worker.on('message', (m) => (m.success ? wRes() : wErr(new Error(m.error))));
-        worker.on('error', wErr);
-        worker.on('exit', (c) => c !== 0 && wErr(new Error(`Exit ${c}`)));
-        worker.postMessage({});
-      });
-
-      // Final optimization with Gifsicle
-      const finalPath = path.join(process.cwd(), `final_${Date.now()}.gif`);
-      tempFiles.push(finalPath);
-      await new Promise((res, rej) => {
-        execFile(gifsicle, ['-O3', '--no-warnings', '-i', outputPath, '-o', finalPath], (e) => (e ? rej(e) : res()));
-      });
-
-      resolve(finalPath);
-    } catch (e) {
-      reject(e);
-    } finally {
-      // Clean up RAM Disk instantly
-      tempFiles.forEach((f) => fs.existsSync(f) && fs.unlinkSync(f));
+  static mergeRanges(ranges) {
+    if (ranges.length === 0) return [];
+    ranges.sort((a, b) => a[0] - b[0]);
+    const merged = [ranges[0]];
+    for (let i = 1; i < ranges.length; i++) {
+      const current = ranges[i];
+      const last = merged[merged.length - 1];
+      if (current[0] <= last[1] + 1) last[1] = Math.max(last[1], current[1]);
+      else merged.push(current);
     }
+    return merged;
   }
 
-  static async prepareClanIcons(client, roleIds) {
-    const icons = [];
-    const ICON_SIZE = 60;
+  /**
+   * CDN URL of each clan's stored icon, or null when it has none. The renderer
+   * fetches, caches and resizes them.
+   */
+  static async resolveClanIconUrls(client, roleIds) {
+    const urls = [];
     for (const roleId of roleIds) {
       try {
         const asset = await DatabaseService.getClanAsset(roleId);
-        if (asset && client) {
-          const buffer = await AssetService.fetchAssetFromLink(client, asset.messageLink);
-          if (buffer) {
-            const resized = await sharp(buffer).resize(ICON_SIZE, ICON_SIZE).toBuffer();
-            icons.push(resized);
-            continue;
-          }
-        }
-        icons.push(null);
-      } catch {
-        icons.push(null);
+        urls.push(asset && client ? await AssetService.resolveAttachmentUrl(client, asset.messageLink) : null);
+      } catch (error) {
+        logger.warn(`[GifService] No icon for clan ${roleId}: ${error.message}`);
+        urls.push(null);
       }
     }
-    return icons;
+    return urls;
   }
 }
 
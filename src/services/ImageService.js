@@ -37,12 +37,33 @@ class ImageService {
     }
   }
 
+  /**
+   * Builds an Error for a failed renderer response. The renderer replies with
+   * `{ code, message, stage }` JSON; fall back to the bare status otherwise.
+   */
+  async rendererError(response, label) {
+    let detail = `status: ${response.status}`;
+    try {
+      const body = await response.json();
+      if (body && body.code) detail = `${response.status} ${body.code} (${body.stage}): ${body.message}`;
+      if (body && Array.isArray(body.fields)) {
+        detail += ` [${body.fields.map((f) => `${f.field}: ${f.message}`).join('; ')}]`;
+      }
+    } catch {
+      // Not JSON (proxy error page, etc.) — the status is all we have.
+    }
+    const error = new Error(`${label} ${detail}`);
+    error.status = response.status;
+    return error;
+  }
+
   async generateRankCard(data) {
     try {
       // 2. Construct Payload
       const payload = {
         username: data.username,
         avatar_url: data.avatarUrl,
+        user_id: data.userId,
         current_xp: data.currentXp,
         next_xp: data.requiredXp,
         rank: data.rank,
@@ -61,7 +82,7 @@ class ImageService {
       });
       timer();
 
-      if (!response.ok) throw new Error(`Renderer HTTP error! status: ${response.status}`);
+      if (!response.ok) throw await this.rendererError(response, 'Renderer HTTP error!');
 
       return Buffer.from(await response.arrayBuffer());
     } catch (error) {
@@ -90,7 +111,7 @@ class ImageService {
               user_id: user.userId,
               username,
               emojis,
-              avatar_url: user.avatarUrl || 'https://cdn.discordapp.com/embed/avatars/0.png',
+              avatar_url: user.avatarUrl || '',
               xp: user.xp,
               rank: user.rank,
             };
@@ -109,7 +130,7 @@ class ImageService {
       });
       timer();
 
-      if (!response.ok) throw new Error(`Renderer HTTP error! status: ${response.status}`);
+      if (!response.ok) throw await this.rendererError(response, 'Renderer HTTP error!');
 
       return Buffer.from(await response.arrayBuffer());
     } catch (error) {
@@ -195,7 +216,7 @@ class ImageService {
         signal: AbortSignal.timeout(15000),
       });
       timer();
-      if (!response.ok) throw new Error(`Role reward base renderer error: ${response.status}`);
+      if (!response.ok) throw await this.rendererError(response, 'Role reward base renderer error:');
       return Buffer.from(await response.arrayBuffer());
     } catch (error) {
       console.error('[ImageService] generateBaseReward failed:', error.message);